@@ -0,0 +1,156 @@
+use crate::config;
+
+use base64::Engine;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use oxhttp::model::{HeaderName, Request};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+lazy_static! {
+    // Verifying bcrypt hashes is expensive, so successful logins are remembered. Only a digest
+    // of the credentials is kept, so passwords don't stay in memory
+    static ref VERIFIED: Mutex<HashSet<[u8; 32]>> = Mutex::new(HashSet::new());
+}
+
+pub fn enabled(cfg: &config::Configuration) -> bool {
+    !cfg.basic_auth_users.is_empty() || !cfg.bearer_tokens.is_empty()
+}
+
+// Compare without returning early to avoid leaking the token through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn verify_basic(cfg: &config::Configuration, credentials: &str) -> bool {
+    let decoded = match base64::engine::general_purpose::STANDARD.decode(credentials.trim()) {
+        Ok(v) => String::from_utf8_lossy(&v).to_string(),
+        Err(_) => return false,
+    };
+    let (user, password) = match decoded.split_once(':') {
+        Some(v) => v,
+        None => return false,
+    };
+    let hash = match cfg.basic_auth_users.get(user) {
+        Some(v) => v,
+        None => {
+            debug!("Unknown user {}", user);
+            // Verify against the hash of another user, otherwise unknown users could be told
+            // apart from wrong passwords by the time of the response
+            if let Some(hash) = cfg.basic_auth_users.values().next() {
+                let _ = bcrypt::verify(password, hash);
+            }
+            return false;
+        }
+    };
+
+    // The hash is part of the key, so changed passwords are verified again after a reload
+    let mut digest = Sha256::new();
+    for part in [user, hash.as_str(), password] {
+        // The length prevents different splits of the same string from having the same digest
+        digest.update((part.len() as u64).to_be_bytes());
+        digest.update(part.as_bytes());
+    }
+    let key: [u8; 32] = digest.finalize().into();
+    if VERIFIED.lock().unwrap().contains(&key) {
+        return true;
+    }
+    match bcrypt::verify(password, hash) {
+        Ok(true) => {
+            VERIFIED.lock().unwrap().insert(key);
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            warn!("Can't verify password of user {}: {}", user, e);
+            false
+        }
+    }
+}
+
+fn verify_bearer(cfg: &config::Configuration, token: &str) -> bool {
+    cfg.bearer_tokens
+        .iter()
+        .any(|v| constant_time_eq(v.as_bytes(), token.trim().as_bytes()))
+}
+
+pub fn is_authorized(cfg: &config::Configuration, req: &Request) -> bool {
+    if !enabled(cfg) {
+        return true;
+    }
+
+    let authorization = match req.header(&HeaderName::AUTHORIZATION) {
+        Some(v) => String::from_utf8_lossy(v).to_string(),
+        None => return false,
+    };
+    let (scheme, credentials) = match authorization.split_once(' ') {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if scheme.eq_ignore_ascii_case("basic") {
+        verify_basic(cfg, credentials)
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        verify_bearer(cfg, credentials)
+    } else {
+        false
+    }
+}
+
+// Value of the WWW-Authenticate header for rejected requests
+pub fn challenge(cfg: &config::Configuration) -> &'static str {
+    if cfg.basic_auth_users.is_empty() {
+        "Bearer"
+    } else {
+        "Basic realm=\"OpenWeatherMap exporter\""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration() -> config::Configuration {
+        let mut cfg = config::test_configuration("");
+        cfg.basic_auth_users
+            .insert("alice".to_string(), bcrypt::hash("secret", 4).unwrap());
+        cfg
+    }
+
+    fn basic(credentials: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(credentials)
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+    }
+
+    #[test]
+    fn test_verify_basic() {
+        let cfg = configuration();
+        assert!(verify_basic(&cfg, &basic("alice:secret")));
+        // Remembered logins must not accept other passwords
+        assert!(verify_basic(&cfg, &basic("alice:secret")));
+        assert!(!verify_basic(&cfg, &basic("alice:wrong")));
+        assert!(!verify_basic(&cfg, &basic("bob:secret")));
+        assert!(!verify_basic(&cfg, &basic("alice")));
+        assert!(!verify_basic(&cfg, "not base64"));
+    }
+
+    #[test]
+    fn test_verify_basic_changed_password() {
+        let mut cfg = configuration();
+        assert!(verify_basic(&cfg, &basic("alice:secret")));
+        cfg.basic_auth_users
+            .insert("alice".to_string(), bcrypt::hash("changed", 4).unwrap());
+        assert!(!verify_basic(&cfg, &basic("alice:secret")));
+        assert!(verify_basic(&cfg, &basic("alice:changed")));
+    }
+}
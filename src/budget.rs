@@ -0,0 +1,214 @@
+use crate::config;
+use crate::exporter;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref BUDGET: Mutex<Option<Budget>> = Mutex::new(None);
+}
+
+struct Budget {
+    cfg: config::BudgetConfiguration,
+    state: BudgetState,
+}
+
+// Counters of the current minute, day and month (UTC)
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct BudgetState {
+    minute: i64,
+    calls_minute: u64,
+    day: String,
+    calls_day: u64,
+    month: String,
+    calls_month: u64,
+}
+
+impl BudgetState {
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let minute = now.timestamp() / 60;
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+
+        if self.minute != minute {
+            self.minute = minute;
+            self.calls_minute = 0;
+        }
+        if self.day != day {
+            self.day = day;
+            self.calls_day = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.calls_month = 0;
+        }
+    }
+}
+
+impl Budget {
+    fn remaining(&self) -> Vec<(&'static str, u64)> {
+        let mut result = Vec::new();
+        if let Some(limit) = self.cfg.calls_per_minute {
+            result.push(("minute", limit.saturating_sub(self.state.calls_minute)));
+        }
+        if let Some(limit) = self.cfg.calls_per_day {
+            result.push(("day", limit.saturating_sub(self.state.calls_day)));
+        }
+        if let Some(limit) = self.cfg.calls_per_month {
+            result.push(("month", limit.saturating_sub(self.state.calls_month)));
+        }
+        result
+    }
+
+    fn set_metrics(&self) {
+        for (period, remaining) in self.remaining() {
+            exporter::BUDGET_REMAINING
+                .with_label_values(&[period])
+                .set(remaining as i64);
+        }
+    }
+}
+
+pub fn init(cfg: &config::Configuration) -> Result<(), Box<dyn Error>> {
+    let mut guard = BUDGET.lock().unwrap();
+    exporter::BUDGET_REMAINING.reset();
+
+    let budget_cfg = match &cfg.budget {
+        Some(v) => v.clone(),
+        None => {
+            *guard = None;
+            return Ok(());
+        }
+    };
+
+    // Keep the current counters if the configuration is reloaded
+    let state = match (guard.take(), &budget_cfg.state_file) {
+        (Some(v), _) => v.state,
+        (None, Some(f)) => load_state(f)?,
+        (None, None) => BudgetState::default(),
+    };
+
+    let mut budget = Budget {
+        cfg: budget_cfg,
+        state,
+    };
+    budget.state.roll(Utc::now());
+    budget.set_metrics();
+
+    *guard = Some(budget);
+    Ok(())
+}
+
+// Account for one API call, returns false if the budget is exhausted
+pub fn acquire() -> bool {
+    let mut guard = BUDGET.lock().unwrap();
+    let budget = match guard.as_mut() {
+        Some(v) => v,
+        None => return true,
+    };
+
+    budget.state.roll(Utc::now());
+    if budget
+        .remaining()
+        .iter()
+        .any(|(_, remaining)| *remaining == 0)
+    {
+        budget.set_metrics();
+        return false;
+    }
+
+    budget.state.calls_minute += 1;
+    budget.state.calls_day += 1;
+    budget.state.calls_month += 1;
+    budget.set_metrics();
+
+    if let Some(f) = &budget.cfg.state_file {
+        if let Err(e) = save_state(f, &budget.state) {
+            error!("Can't save API call budget to {}: {}", f, e);
+        }
+    }
+
+    true
+}
+
+pub fn update_metrics() {
+    if let Some(budget) = BUDGET.lock().unwrap().as_mut() {
+        budget.state.roll(Utc::now());
+        budget.set_metrics();
+    }
+}
+
+fn load_state(f: &str) -> Result<BudgetState, Box<dyn Error>> {
+    let raw = match fs::read_to_string(f) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!(
+                "API call budget file {} not found, starting with empty budget",
+                f
+            );
+            return Ok(BudgetState::default());
+        }
+        Err(e) => bail!("can't read {}: {}", f, e),
+    };
+
+    let state: BudgetState = match serde_json::from_str(&raw) {
+        Ok(v) => v,
+        Err(e) => bail!("can't parse {}: {}", f, e),
+    };
+    Ok(state)
+}
+
+fn save_state(f: &str, state: &BudgetState) -> Result<(), Box<dyn Error>> {
+    let tmp = format!("{}.tmp", f);
+    fs::write(&tmp, serde_json::to_string(state)?)?;
+    fs::rename(&tmp, f)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_roll() {
+        let mut state = BudgetState::default();
+        state.roll(Utc.with_ymd_and_hms(2024, 1, 31, 23, 59, 0).unwrap());
+        state.calls_minute = 1;
+        state.calls_day = 2;
+        state.calls_month = 3;
+
+        state.roll(Utc.with_ymd_and_hms(2024, 1, 31, 23, 59, 59).unwrap());
+        assert_eq!(
+            (state.calls_minute, state.calls_day, state.calls_month),
+            (1, 2, 3)
+        );
+
+        state.roll(Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap());
+        assert_eq!(
+            (state.calls_minute, state.calls_day, state.calls_month),
+            (0, 0, 0)
+        );
+        assert_eq!(state.day, "2024-02-01");
+        assert_eq!(state.month, "2024-02");
+    }
+
+    #[test]
+    fn test_remaining() {
+        let budget = Budget {
+            cfg: serde_yaml::from_str("calls_per_minute: 60\ncalls_per_month: 1000").unwrap(),
+            state: BudgetState {
+                calls_minute: 61,
+                calls_month: 10,
+                ..Default::default()
+            },
+        };
+        assert_eq!(budget.remaining(), vec![("minute", 0), ("month", 990)]);
+    }
+}
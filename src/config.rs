@@ -1,31 +1,1256 @@
+use crate::constants;
+
+use ipnet::IpNet;
+use log::warn;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::net::IpAddr;
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Configuration {
     pub api_key: String,
-    pub locations: Vec<String>,
+    pub locations: Vec<Location>,
+    pub listen: Option<String>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_min_version: Option<String>,
+    pub tls_client_ca_file: Option<String>,
+    pub web_config_file: Option<String>,
+    pub allowed_networks: Option<Vec<String>>,
+    // Parsed allowed_networks, connections are checked before they are served
+    #[serde(skip)]
+    pub allowed_ip_networks: Option<Vec<IpNet>>,
+    #[serde(default)]
+    pub bearer_tokens: Vec<String>,
+    // Set from the web configuration file
+    #[serde(skip)]
+    pub basic_auth_users: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub tls_max_version: Option<String>,
+    #[serde(skip)]
+    pub tls_client_auth_optional: bool,
+    #[serde(skip)]
+    pub tls_client_allowed_sans: Vec<String>,
+    #[serde(skip)]
+    pub http_headers: BTreeMap<String, String>,
     pub timeout: Option<u64>,
+    pub proxy: Option<ProxyConfiguration>,
+    pub ca_file: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    pub retry: Option<RetryConfiguration>,
+    pub budget: Option<BudgetConfiguration>,
+    pub refresh_interval: Option<u64>,
+    pub max_data_age: Option<u64>,
+    pub scrape_timeout_offset: Option<f64>,
+    pub startup_check: Option<bool>,
+    pub watch_config: Option<bool>,
+    pub pushgateway: Option<PushgatewayConfiguration>,
+    pub remote_write: Option<RemoteWriteConfiguration>,
+    pub influxdb: Option<InfluxDBConfiguration>,
+    pub graphite: Option<GraphiteConfiguration>,
+    pub otlp: Option<OtlpConfiguration>,
+    pub mqtt: Option<MqttConfiguration>,
+    pub probe: Option<ProbeConfiguration>,
+}
+
+// Locations can be given by name (e.g. 'London,gb'), by city ID or by coordinates
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Location {
+    Name(String),
+    Id { id: u64 },
+    Coordinates { lat: f64, lon: f64 },
+}
+
+impl Location {
+    // Query parameters of the OpenWeatherMap API, they are encoded when building the URL
+    pub fn query(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Location::Name(v) => vec![("q", v.clone())],
+            Location::Id { id } => vec![("id", id.to_string())],
+            Location::Coordinates { lat, lon } => {
+                vec![("lat", lat.to_string()), ("lon", lon.to_string())]
+            }
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Name(v) => write!(f, "{}", v),
+            Location::Id { id } => write!(f, "id={}", id),
+            Location::Coordinates { lat, lon } => write!(f, "lat={},lon={}", lat, lon),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfiguration {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub no_proxy: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfiguration {
+    pub retries: Option<u32>,
+    pub initial_delay: Option<u64>,
+    pub max_delay: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetConfiguration {
+    pub calls_per_minute: Option<u64>,
+    pub calls_per_day: Option<u64>,
+    pub calls_per_month: Option<u64>,
+    pub state_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushgatewayConfiguration {
+    pub url: String,
+    pub job: Option<String>,
+    #[serde(default)]
+    pub grouping: BTreeMap<String, String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub method: PushMethod,
+}
+
+// PUT replaces all metrics of the group, POST only metrics with the same name
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PushMethod {
+    #[default]
+    Put,
+    Post,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteWriteConfiguration {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub max_queue: Option<usize>,
+}
+
+// Line protocol is served at /influx, if url is set it is also sent to the InfluxDB 2.x write API
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxDBConfiguration {
+    pub measurement: Option<String>,
+    pub url: Option<String>,
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraphiteConfiguration {
+    pub address: String,
+    pub prefix: Option<String>,
+    pub template: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfiguration {
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub resource_attributes: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfiguration {
+    pub address: String,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: Option<String>,
+    pub qos: Option<u8>,
+    pub homeassistant_discovery: Option<bool>,
+    pub discovery_prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeConfiguration {
+    pub allowed_locations: Option<Vec<Location>>,
+    // Every probe uses the API key, so probing any location must be enabled explicitly
+    pub allow_all_locations: Option<bool>,
+}
+
+// Web configuration file of the Prometheus exporter-toolkit, see
+// https://github.com/prometheus/exporter-toolkit/blob/master/docs/web-configuration.md
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfiguration {
+    pub tls_server_config: Option<WebTlsConfiguration>,
+    pub http_server_config: Option<WebHttpConfiguration>,
+    #[serde(default)]
+    pub basic_auth_users: BTreeMap<String, String>,
+    // Not supported, only accepted for compatibility
+    pub rate_limit: Option<serde_yaml::Value>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebTlsConfiguration {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub client_ca: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub client_auth_type: Option<String>,
+    pub client_ca_file: Option<String>,
+    pub cipher_suites: Option<Vec<String>>,
+    pub curve_preferences: Option<Vec<String>>,
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+    pub prefer_server_cipher_suites: Option<bool>,
+    pub client_allowed_sans: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebHttpConfiguration {
+    pub http2: Option<bool>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+// TLS versions of the web configuration file, versions before TLS 1.2 aren't supported by rustls
+fn web_tls_version(version: &str, f: &str) -> Result<&'static str, Box<dyn Error>> {
+    match version {
+        "TLS10" | "TLS11" | "TLS12" => Ok("1.2"),
+        "TLS13" => Ok("1.3"),
+        _ => bail!(
+            "unsupported TLS version {} in web configuration file {}",
+            version,
+            f
+        ),
+    }
+}
+
+// Use basic authentication users, HTTP headers and TLS settings of the web configuration file.
+// Settings that aren't supported are ignored with a warning, unless ignoring them would make
+// the server less secure than configured
+fn apply_web_config(config: &mut Configuration, f: &str) -> Result<(), Box<dyn Error>> {
+    let unparsed = match fs::read_to_string(f) {
+        Ok(v) => v,
+        Err(e) => bail!("can't read web configuration file {}: {}", f, e),
+    };
+    let web_config: WebConfiguration = match serde_yaml::from_str(&unparsed) {
+        Ok(v) => v,
+        Err(e) => bail!("can't parse web configuration file {}: {}", f, e),
+    };
+
+    for (user, hash) in &web_config.basic_auth_users {
+        if user.is_empty() || user.contains(':') {
+            bail!(
+                "invalid user name \"{}\" in web configuration file {}",
+                user,
+                f
+            );
+        }
+        if hash.parse::<bcrypt::HashParts>().is_err() {
+            bail!(
+                "password of user {} in web configuration file {} is not a bcrypt hash",
+                user,
+                f
+            );
+        }
+    }
+    config.basic_auth_users = web_config.basic_auth_users;
+
+    if web_config.rate_limit.is_some() {
+        warn!(
+            "Setting rate_limit of web configuration file {} is not supported, ignoring it",
+            f
+        );
+    }
+
+    if let Some(http) = web_config.http_server_config {
+        if http.http2 == Some(true) {
+            warn!(
+                "Setting http2 of web configuration file {} is not supported, ignoring it",
+                f
+            );
+        }
+        for (name, value) in &http.headers {
+            if name.parse::<oxhttp::model::HeaderName>().is_err()
+                || value.parse::<oxhttp::model::HeaderValue>().is_err()
+            {
+                bail!(
+                    "invalid HTTP header {} in web configuration file {}",
+                    name,
+                    f
+                );
+            }
+        }
+        config.http_headers = http.headers;
+    }
+
+    if let Some(tls) = web_config.tls_server_config {
+        if config.tls_cert_file.is_some() || config.tls_key_file.is_some() {
+            bail!(
+                "TLS can't be configured in the configuration file and the web configuration file {}",
+                f
+            );
+        }
+        if tls.cert.is_some() || tls.key.is_some() || tls.client_ca.is_some() {
+            bail!(
+                "inline certificates and keys are not supported, use cert_file, key_file and client_ca_file in web configuration file {}",
+                f
+            );
+        }
+        let (cert_file, key_file) = match (tls.cert_file, tls.key_file) {
+            (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+            _ => bail!(
+                "TLS requires cert_file and key_file in web configuration file {}",
+                f
+            ),
+        };
+
+        config.tls_min_version = match tls.min_version.as_deref().map(|v| web_tls_version(v, f)) {
+            Some(Ok("1.3")) => Some("1.3".to_string()),
+            Some(Err(e)) => return Err(e),
+            _ => None,
+        };
+        config.tls_max_version = match tls.max_version.as_deref() {
+            None | Some("TLS13") => None,
+            Some("TLS12") => Some("1.2".to_string()),
+            Some(v) => bail!(
+                "unsupported max_version {} in web configuration file {}",
+                v,
+                f
+            ),
+        };
+        if config.tls_min_version.is_some() && config.tls_max_version.is_some() {
+            bail!(
+                "min_version is greater than max_version in web configuration file {}",
+                f
+            );
+        }
+        if matches!(tls.min_version.as_deref(), Some("TLS10") | Some("TLS11")) {
+            warn!("TLS versions before 1.2 are not supported, using TLS 1.2 as minimal version");
+        }
+
+        for (key, set) in [
+            ("cipher_suites", tls.cipher_suites.is_some()),
+            ("curve_preferences", tls.curve_preferences.is_some()),
+            (
+                "prefer_server_cipher_suites",
+                tls.prefer_server_cipher_suites.is_some(),
+            ),
+        ] {
+            if set {
+                warn!(
+                    "Setting {} of web configuration file {} is not supported, ignoring it",
+                    key, f
+                );
+            }
+        }
+
+        let client_auth_type = tls.client_auth_type.as_deref().unwrap_or("NoClientCert");
+        config.tls_client_auth_optional = client_auth_type == "VerifyClientCertIfGiven";
+        config.tls_client_ca_file = match client_auth_type {
+            "NoClientCert" => None,
+            // The certificate isn't verified either way
+            "RequestClientCert" => {
+                warn!("Client certificates are not requested, client_auth_type RequestClientCert behaves like NoClientCert");
+                None
+            }
+            "RequireAnyClientCert" => match tls.client_ca_file {
+                Some(v) => {
+                    warn!("Client certificates can't be accepted without verification, client_auth_type RequireAnyClientCert behaves like RequireAndVerifyClientCert");
+                    Some(v)
+                }
+                None => bail!(
+                    "client_auth_type RequireAnyClientCert is only supported with client_ca_file in web configuration file {}",
+                    f
+                ),
+            },
+            "VerifyClientCertIfGiven" | "RequireAndVerifyClientCert" => match tls.client_ca_file {
+                Some(v) => Some(v),
+                None => bail!(
+                    "client_auth_type {} requires client_ca_file in web configuration file {}",
+                    client_auth_type,
+                    f
+                ),
+            },
+            v => bail!(
+                "unsupported client_auth_type {} in web configuration file {}",
+                v,
+                f
+            ),
+        };
+        config.tls_client_allowed_sans = tls.client_allowed_sans.unwrap_or_default();
+        if !config.tls_client_allowed_sans.is_empty() && config.tls_client_ca_file.is_none() {
+            bail!(
+                "client_allowed_sans requires client certificates in web configuration file {}",
+                f
+            );
+        }
+
+        config.tls_cert_file = Some(cert_file);
+        config.tls_key_file = Some(key_file);
+    }
+    Ok(())
 }
 
 pub fn parse_config_file(f: &str) -> Result<Configuration, Box<dyn Error>> {
     let unparsed = fs::read_to_string(f)?;
-    let config: Configuration = serde_yaml::from_str(unparsed.as_str())?;
+    let mut config: Configuration = serde_yaml::from_str(unparsed.as_str())?;
+
+    if let Some(web_config_file) = config.web_config_file.clone() {
+        apply_web_config(&mut config, &web_config_file)?;
+    }
 
-    validate_configuration(&config)?;
+    let errors = validate_configuration(&config, &unparsed);
+    if !errors.is_empty() {
+        bail!("{}", errors.join("\n"));
+    }
+    parse_allowed_networks(&mut config);
 
     Ok(config)
 }
 
-fn validate_configuration(cfg: &Configuration) -> Result<(), Box<dyn Error>> {
+// The networks are validated before, so they are parsed once instead of for every connection
+fn parse_allowed_networks(config: &mut Configuration) {
+    config.allowed_ip_networks = config
+        .allowed_networks
+        .as_ref()
+        .map(|v| v.iter().filter_map(|n| parse_network(n).ok()).collect());
+}
+
+// Path of every key and list item of a YAML document with its line number, list items
+// have the path of their list. Flow style and multi-line values aren't taken into account,
+// this is only used to point to the line of a configuration error.
+fn yaml_lines(raw: &str) -> Vec<(usize, Vec<&str>, bool)> {
+    let mut result = Vec::new();
+    let mut parents: Vec<(usize, &str)> = Vec::new();
+
+    for (i, line) in raw.lines().enumerate() {
+        let mut content = line.trim_start();
+        if content.is_empty() || content.starts_with('#') || content.starts_with("---") {
+            continue;
+        }
+        let mut indent = line.len() - content.len();
+        while let Some(rest) = content.strip_prefix('-') {
+            let item = rest.trim_start();
+            if item.len() == rest.len() && !rest.is_empty() {
+                break;
+            }
+            while parents.last().is_some_and(|(v, _)| *v >= indent) {
+                parents.pop();
+            }
+            result.push((i + 1, parents.iter().map(|(_, k)| *k).collect(), true));
+            indent += content.len() - item.len();
+            content = item;
+        }
+
+        let key = match content.split_once(": ").or_else(|| content.split_once(':')) {
+            Some((key, _)) => key.trim().trim_matches(['\'', '"']),
+            None => continue,
+        };
+        while parents.last().is_some_and(|(v, _)| *v >= indent) {
+            parents.pop();
+        }
+        parents.push((indent, key));
+        result.push((i + 1, parents.iter().map(|(_, k)| *k).collect(), false));
+    }
+    result
+}
+
+// Line number of a key given by its path, e.g. "proxy.url"
+fn find_key(raw: &str, path: &str) -> Option<usize> {
+    let path: Vec<&str> = path.split('.').collect();
+    yaml_lines(raw)
+        .into_iter()
+        .find(|(_, v, item)| !item && *v == path)
+        .map(|(line, _, _)| line)
+}
+
+// Line number of the n-th item of the list given by its path
+fn find_item(raw: &str, path: &str, n: usize) -> Option<usize> {
+    let path: Vec<&str> = path.split('.').collect();
+    yaml_lines(raw)
+        .into_iter()
+        .filter(|(_, v, item)| *item && *v == path)
+        .nth(n)
+        .map(|(line, _, _)| line)
+}
+
+fn config_error(raw: &str, path: &str, message: &str) -> String {
+    match find_key(raw, path) {
+        Some(line) => format!("line {}: {}", line, message),
+        None => message.to_string(),
+    }
+}
+
+// Networks in CIDR notation, single addresses are accepted as host networks
+pub fn parse_network(network: &str) -> Result<IpNet, Box<dyn Error>> {
+    if let Ok(address) = network.parse::<IpAddr>() {
+        return Ok(IpNet::from(address));
+    }
+    match network.parse::<IpNet>() {
+        Ok(v) => Ok(v),
+        Err(e) => bail!("invalid network {}: {}", network, e),
+    }
+}
+
+// Check syntax of <host>:<port>, without resolving the host
+pub fn validate_address(address: &str) -> Result<(), Box<dyn Error>> {
+    let (host, port) = match address.rsplit_once(':') {
+        Some(v) => v,
+        None => bail!("missing port in {}", address),
+    };
+    if host.is_empty() {
+        bail!("missing host in {}", address);
+    }
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        bail!("IPv6 address in {} must be enclosed in brackets", address);
+    }
+    if port.parse::<u16>().is_err() {
+        bail!("invalid port {} in {}", port, address);
+    }
+    Ok(())
+}
+
+// Also used for the targets of the probe endpoint
+pub fn validate_location(location: &Location) -> Vec<String> {
+    let mut errors = Vec::new();
+    match location {
+        Location::Name(v) => {
+            if v.trim().is_empty() {
+                errors.push("Empty location name".to_string());
+            }
+        }
+        Location::Id { .. } => {}
+        Location::Coordinates { lat, lon } => {
+            if !lat.is_finite() || !(-90.0..=90.0).contains(lat) {
+                errors.push(format!(
+                    "Invalid latitude {}, must be between -90 and 90",
+                    lat
+                ));
+            }
+            if !lon.is_finite() || !(-180.0..=180.0).contains(lon) {
+                errors.push(format!(
+                    "Invalid longitude {}, must be between -180 and 180",
+                    lon
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn validate_configuration(cfg: &Configuration, raw: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+
     if cfg.api_key.is_empty() {
-        bail!("Missing API key");
+        errors.push(config_error(raw, "api_key", "Missing API key"));
     }
 
     if cfg.locations.is_empty() {
-        bail!("No locations to query");
+        errors.push(config_error(raw, "locations", "No locations to query"));
     }
 
-    Ok(())
+    let mut seen = HashSet::new();
+    for (i, location) in cfg.locations.iter().enumerate() {
+        let name = location.to_string();
+        let message_prefix = match find_item(raw, "locations", i) {
+            Some(line) => format!("line {}: ", line),
+            None => String::new(),
+        };
+
+        for message in validate_location(location) {
+            errors.push(format!("{}{}", message_prefix, message));
+        }
+
+        if !seen.insert(name.to_lowercase()) {
+            errors.push(format!("{}Duplicate location {}", message_prefix, name));
+        }
+    }
+
+    if let Some(listen) = &cfg.listen {
+        if let Err(e) = validate_address(listen) {
+            errors.push(config_error(
+                raw,
+                "listen",
+                &format!("Invalid listen address: {}", e),
+            ));
+        }
+    }
+
+    if let Some(timeout) = cfg.timeout {
+        if timeout == 0 || timeout > constants::MAX_HTTP_CLIENT_TIMEOUT {
+            errors.push(config_error(
+                raw,
+                "timeout",
+                &format!(
+                    "Timeout must be between 1 and {} seconds",
+                    constants::MAX_HTTP_CLIENT_TIMEOUT
+                ),
+            ));
+        }
+    }
+
+    if let Some(proxy) = &cfg.proxy {
+        if proxy.url.is_empty() {
+            errors.push(config_error(raw, "proxy", "Missing proxy URL"));
+        } else if let Err(e) = reqwest::Url::parse(&proxy.url) {
+            errors.push(config_error(
+                raw,
+                "proxy.url",
+                &format!("Invalid proxy URL {}: {}", proxy.url, e),
+            ));
+        }
+        if proxy.username.is_some() != proxy.password.is_some() {
+            errors.push(config_error(
+                raw,
+                "proxy",
+                "Proxy authentication requires both username and password",
+            ));
+        }
+    }
+
+    for (key, file) in [
+        ("ca_file", &cfg.ca_file),
+        ("client_certificate", &cfg.client_certificate),
+        ("client_key", &cfg.client_key),
+    ] {
+        if let Some(file) = file {
+            if let Err(e) = fs::metadata(file) {
+                errors.push(config_error(
+                    raw,
+                    key,
+                    &format!("Can't access {}: {}", file, e),
+                ));
+            }
+        }
+    }
+
+    for (key, file) in [
+        ("tls_cert_file", &cfg.tls_cert_file),
+        ("tls_key_file", &cfg.tls_key_file),
+        ("tls_client_ca_file", &cfg.tls_client_ca_file),
+    ] {
+        if let Some(file) = file {
+            if let Err(e) = fs::metadata(file) {
+                errors.push(config_error(
+                    raw,
+                    key,
+                    &format!("Can't access {}: {}", file, e),
+                ));
+            }
+        }
+    }
+
+    if cfg.tls_cert_file.is_some() != cfg.tls_key_file.is_some() {
+        errors.push(config_error(
+            raw,
+            if cfg.tls_cert_file.is_some() {
+                "tls_cert_file"
+            } else {
+                "tls_key_file"
+            },
+            "TLS requires both tls_cert_file and tls_key_file",
+        ));
+    }
+    if cfg.tls_cert_file.is_none()
+        && (cfg.tls_min_version.is_some() || cfg.tls_client_ca_file.is_some())
+    {
+        errors.push(config_error(
+            raw,
+            if cfg.tls_min_version.is_some() {
+                "tls_min_version"
+            } else {
+                "tls_client_ca_file"
+            },
+            "TLS options require tls_cert_file and tls_key_file",
+        ));
+    }
+    if let Some(version) = &cfg.tls_min_version {
+        if !constants::TLS_VERSIONS.contains(&version.as_str()) {
+            errors.push(config_error(
+                raw,
+                "tls_min_version",
+                &format!(
+                    "Unsupported minimal TLS version {}, supported versions are {}",
+                    version,
+                    constants::TLS_VERSIONS.join(", ")
+                ),
+            ));
+        }
+    }
+
+    if let Some(networks) = &cfg.allowed_networks {
+        for network in networks {
+            if let Err(e) = parse_network(network) {
+                errors.push(config_error(
+                    raw,
+                    "allowed_networks",
+                    &format!("Invalid allowed network: {}", e),
+                ));
+            }
+        }
+    }
+
+    if cfg.bearer_tokens.iter().any(|v| v.is_empty()) {
+        errors.push(config_error(
+            raw,
+            "bearer_tokens",
+            "Bearer tokens must not be empty",
+        ));
+    }
+
+    if cfg.client_certificate.is_some() != cfg.client_key.is_some() {
+        errors.push(config_error(
+            raw,
+            if cfg.client_certificate.is_some() {
+                "client_certificate"
+            } else {
+                "client_key"
+            },
+            "Client certificate authentication requires both client_certificate and client_key",
+        ));
+    }
+
+    if let Some(retry) = &cfg.retry {
+        let initial_delay = retry
+            .initial_delay
+            .unwrap_or(constants::DEFAULT_RETRY_INITIAL_DELAY);
+        let max_delay = retry
+            .max_delay
+            .unwrap_or(constants::DEFAULT_RETRY_MAX_DELAY);
+        if initial_delay == 0 {
+            errors.push(config_error(
+                raw,
+                "retry.initial_delay",
+                "Initial retry delay must be greater than 0",
+            ));
+        }
+        if max_delay < initial_delay {
+            errors.push(config_error(
+                raw,
+                "retry.max_delay",
+                "Maximal retry delay must not be less than the initial retry delay",
+            ));
+        }
+    }
+
+    if cfg.refresh_interval == Some(0) {
+        errors.push(config_error(
+            raw,
+            "refresh_interval",
+            "Refresh interval must be greater than 0",
+        ));
+    }
+
+    if let Some(offset) = cfg.scrape_timeout_offset {
+        if !offset.is_finite() || offset < 0.0 {
+            errors.push(config_error(
+                raw,
+                "scrape_timeout_offset",
+                "Scrape timeout offset must not be negative",
+            ));
+        }
+    }
+
+    if cfg.max_data_age == Some(0) {
+        errors.push(config_error(
+            raw,
+            "max_data_age",
+            "Maximal age of weather data must be greater than 0",
+        ));
+    }
+
+    if let Some(budget) = &cfg.budget {
+        if budget.calls_per_minute.is_none()
+            && budget.calls_per_day.is_none()
+            && budget.calls_per_month.is_none()
+        {
+            errors.push(config_error(
+                raw,
+                "budget",
+                "API call budget requires at least one limit",
+            ));
+        }
+        for (key, limit) in [
+            ("budget.calls_per_minute", budget.calls_per_minute),
+            ("budget.calls_per_day", budget.calls_per_day),
+            ("budget.calls_per_month", budget.calls_per_month),
+        ] {
+            if limit == Some(0) {
+                errors.push(config_error(
+                    raw,
+                    key,
+                    "API call limit must be greater than 0",
+                ));
+            }
+        }
+    }
+
+    if let Some(pushgateway) = &cfg.pushgateway {
+        if let Err(e) = reqwest::Url::parse(&pushgateway.url) {
+            errors.push(config_error(
+                raw,
+                "pushgateway.url",
+                &format!("Invalid Pushgateway URL {}: {}", pushgateway.url, e),
+            ));
+        }
+        if pushgateway.username.is_some() != pushgateway.password.is_some() {
+            errors.push(config_error(
+                raw,
+                "pushgateway",
+                "Pushgateway authentication requires both username and password",
+            ));
+        }
+        if pushgateway.job.as_deref() == Some("") {
+            errors.push(config_error(
+                raw,
+                "pushgateway.job",
+                "Pushgateway job must not be empty",
+            ));
+        }
+        for (name, value) in &pushgateway.grouping {
+            if name.is_empty() || name == "job" || value.is_empty() {
+                errors.push(config_error(
+                    raw,
+                    "pushgateway.grouping",
+                    &format!("Invalid Pushgateway grouping label {}={}", name, value),
+                ));
+            }
+        }
+    }
+
+    if let Some(remote_write) = &cfg.remote_write {
+        if let Err(e) = reqwest::Url::parse(&remote_write.url) {
+            errors.push(config_error(
+                raw,
+                "remote_write.url",
+                &format!("Invalid remote write URL {}: {}", remote_write.url, e),
+            ));
+        }
+        if remote_write.username.is_some() != remote_write.password.is_some() {
+            errors.push(config_error(
+                raw,
+                "remote_write",
+                "Remote write authentication requires both username and password",
+            ));
+        }
+        if remote_write.username.is_some() && remote_write.bearer_token.is_some() {
+            errors.push(config_error(
+                raw,
+                "remote_write.bearer_token",
+                "Remote write can't use basic authentication and a bearer token at the same time",
+            ));
+        }
+        if remote_write.max_queue == Some(0) {
+            errors.push(config_error(
+                raw,
+                "remote_write.max_queue",
+                "Remote write queue size must be greater than 0",
+            ));
+        }
+        for name in remote_write.labels.keys() {
+            if name.is_empty() || name.starts_with("__") {
+                errors.push(config_error(
+                    raw,
+                    "remote_write.labels",
+                    &format!("Invalid remote write label name {}", name),
+                ));
+            }
+        }
+    }
+
+    if let Some(influxdb) = &cfg.influxdb {
+        if influxdb.measurement.as_deref() == Some("") {
+            errors.push(config_error(
+                raw,
+                "influxdb.measurement",
+                "InfluxDB measurement must not be empty",
+            ));
+        }
+        if let Some(url) = &influxdb.url {
+            if let Err(e) = reqwest::Url::parse(url) {
+                errors.push(config_error(
+                    raw,
+                    "influxdb.url",
+                    &format!("Invalid InfluxDB URL {}: {}", url, e),
+                ));
+            }
+            if influxdb.org.is_none() || influxdb.bucket.is_none() {
+                errors.push(config_error(
+                    raw,
+                    "influxdb",
+                    "Writing to InfluxDB requires org and bucket",
+                ));
+            }
+        }
+    }
+
+    if let Some(graphite) = &cfg.graphite {
+        if let Err(e) = validate_address(&graphite.address) {
+            errors.push(config_error(
+                raw,
+                "graphite.address",
+                &format!("Invalid carbon server address: {}", e),
+            ));
+        }
+        if let Some(template) = &graphite.template {
+            if !template.contains("{metric}") {
+                errors.push(config_error(
+                    raw,
+                    "graphite.template",
+                    "Graphite path template must contain {metric}",
+                ));
+            }
+        }
+    }
+
+    if let Some(otlp) = &cfg.otlp {
+        if let Err(e) = reqwest::Url::parse(&otlp.url) {
+            errors.push(config_error(
+                raw,
+                "otlp.url",
+                &format!("Invalid OTLP URL {}: {}", otlp.url, e),
+            ));
+        }
+        for (name, value) in &otlp.headers {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(value).is_err()
+            {
+                errors.push(config_error(
+                    raw,
+                    "otlp.headers",
+                    &format!("Invalid OTLP header {}", name),
+                ));
+            }
+        }
+    }
+
+    if let Some(mqtt) = &cfg.mqtt {
+        if let Err(e) = validate_address(&mqtt.address) {
+            errors.push(config_error(
+                raw,
+                "mqtt.address",
+                &format!("Invalid MQTT broker address: {}", e),
+            ));
+        }
+        if mqtt.username.is_some() != mqtt.password.is_some() {
+            errors.push(config_error(
+                raw,
+                "mqtt",
+                "MQTT authentication requires both username and password",
+            ));
+        }
+        if mqtt.qos.is_some_and(|v| v > 2) {
+            errors.push(config_error(raw, "mqtt.qos", "MQTT QoS must be 0, 1 or 2"));
+        }
+        for (key, prefix) in [
+            ("mqtt.topic_prefix", &mqtt.topic_prefix),
+            ("mqtt.discovery_prefix", &mqtt.discovery_prefix),
+        ] {
+            if let Some(prefix) = prefix {
+                if prefix.is_empty() || prefix.contains(['+', '#']) {
+                    errors.push(config_error(
+                        raw,
+                        key,
+                        &format!("Invalid MQTT topic prefix {}", prefix),
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(probe) = &cfg.probe {
+        match (&probe.allowed_locations, probe.allow_all_locations) {
+            (Some(_), Some(true)) => errors.push(config_error(
+                raw,
+                "probe.allow_all_locations",
+                "Probes can't be limited by allowed_locations and allow all locations at the same time",
+            )),
+            (None, None) | (None, Some(false)) => errors.push(config_error(
+                raw,
+                "probe",
+                "Probes require allowed_locations or allow_all_locations",
+            )),
+            _ => {}
+        };
+    }
+    if let Some(allowed_locations) = cfg
+        .probe
+        .as_ref()
+        .and_then(|v| v.allowed_locations.as_ref())
+    {
+        if allowed_locations.is_empty() {
+            errors.push(config_error(
+                raw,
+                "probe.allowed_locations",
+                "List of allowed probe locations must not be empty",
+            ));
+        }
+        for (i, location) in allowed_locations.iter().enumerate() {
+            for message in validate_location(location) {
+                errors.push(match find_item(raw, "probe.allowed_locations", i) {
+                    Some(line) => format!("line {}: {}", line, message),
+                    None => message,
+                });
+            }
+        }
+    }
+
+    errors
+}
+
+// Minimal configuration for tests of all modules, locations default to Berlin
+#[cfg(test)]
+pub fn test_configuration(yaml: &str) -> Configuration {
+    let locations = if yaml.lines().any(|v| v.starts_with("locations:")) {
+        ""
+    } else {
+        "locations: [Berlin]\n"
+    };
+    let mut config = serde_yaml::from_str(&format!("api_key: x\n{}{}", locations, yaml)).unwrap();
+    parse_allowed_networks(&mut config);
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_query() {
+        assert_eq!(
+            Location::Name("London,gb".to_string()).query(),
+            vec![("q", "London,gb".to_string())]
+        );
+        assert_eq!(
+            Location::Id { id: 2950159 }.query(),
+            vec![("id", "2950159".to_string())]
+        );
+        assert_eq!(
+            Location::Coordinates {
+                lat: 52.52,
+                lon: -13.5
+            }
+            .query(),
+            vec![("lat", "52.52".to_string()), ("lon", "-13.5".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_validate_location() {
+        assert!(validate_location(&Location::Name("Berlin".to_string())).is_empty());
+        assert!(validate_location(&Location::Coordinates {
+            lat: 90.0,
+            lon: -180.0
+        })
+        .is_empty());
+        assert_eq!(validate_location(&Location::Name(" ".to_string())).len(), 1);
+        assert_eq!(
+            validate_location(&Location::Coordinates {
+                lat: 90.5,
+                lon: f64::NAN
+            })
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!(
+            parse_network("192.168.0.0/16").unwrap(),
+            "192.168.0.0/16".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(
+            parse_network("10.0.0.1").unwrap(),
+            "10.0.0.1/32".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(
+            parse_network("::1").unwrap(),
+            "::1/128".parse::<IpNet>().unwrap()
+        );
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("localhost").is_err());
+    }
+
+    fn validate(raw: &str) -> Vec<String> {
+        validate_configuration(&serde_yaml::from_str(raw).unwrap(), raw)
+    }
+
+    const RAW: &str = "# Example
+api_key: 'x'
+proxy:
+  url: http://proxy:3128
+locations:
+  - Berlin
+  - id: 2950159
+  - lat: 52.52
+    lon: 13.41
+probe:
+  allowed_locations:
+    - London
+";
+
+    #[test]
+    fn test_find_key() {
+        assert_eq!(find_key(RAW, "api_key"), Some(2));
+        assert_eq!(find_key(RAW, "proxy.url"), Some(4));
+        assert_eq!(find_key(RAW, "probe.allowed_locations"), Some(11));
+        assert_eq!(find_key(RAW, "url"), None);
+        assert_eq!(find_key(RAW, "timeout"), None);
+    }
+
+    #[test]
+    fn test_find_item() {
+        assert_eq!(find_item(RAW, "locations", 0), Some(6));
+        assert_eq!(find_item(RAW, "locations", 1), Some(7));
+        assert_eq!(find_item(RAW, "locations", 2), Some(8));
+        assert_eq!(find_item(RAW, "locations", 3), None);
+        assert_eq!(find_item(RAW, "probe.allowed_locations", 0), Some(12));
+    }
+
+    #[test]
+    fn test_validate_configuration() {
+        assert!(validate("api_key: x\nlocations: [Berlin]\n").is_empty());
+        assert_eq!(
+            validate("api_key: ''\nlocations:\n  - Berlin\n  - lat: 91\n    lon: 0\n  - berlin\n"),
+            vec![
+                "line 1: Missing API key",
+                "line 4: Invalid latitude 91, must be between -90 and 90",
+                "line 6: Duplicate location berlin",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_address() {
+        assert!(validate_address("localhost:9000").is_ok());
+        assert!(validate_address("[::1]:9000").is_ok());
+        assert!(validate_address("localhost").is_err());
+        assert!(validate_address(":9000").is_err());
+        assert!(validate_address("::1:9000").is_err());
+        assert!(validate_address("localhost:http").is_err());
+    }
+
+    // Applies a web configuration file to a minimal configuration
+    fn web_config(name: &str, yaml: &str) -> Result<Configuration, Box<dyn Error>> {
+        let f = std::env::temp_dir().join(format!(
+            "openweathermap-exporter-test-{}-{}.yml",
+            std::process::id(),
+            name
+        ));
+        fs::write(&f, yaml).unwrap();
+        let mut cfg = test_configuration("");
+        let result = apply_web_config(&mut cfg, f.to_str().unwrap());
+        fs::remove_file(&f).unwrap();
+        result.map(|_| cfg)
+    }
+
+    #[test]
+    fn test_web_config() {
+        let cfg = web_config(
+            "full",
+            "
+tls_server_config:
+  cert_file: server.pem
+  key_file: server.key
+  client_auth_type: VerifyClientCertIfGiven
+  client_ca_file: ca.pem
+  client_allowed_sans: [client.example]
+  min_version: TLS13
+  cipher_suites: [TLS_AES_128_GCM_SHA256]
+http_server_config:
+  http2: true
+  headers:
+    X-Frame-Options: deny
+basic_auth_users:
+  alice: $2y$04$Zv1W2jWh2KCGgBQ2B6cSqOTvW3HPc9hlAmkU5Xzax9mUTW0Fj3ZSi
+rate_limit:
+  interval: 1s
+",
+        )
+        .unwrap();
+        assert_eq!(cfg.tls_cert_file.as_deref(), Some("server.pem"));
+        assert_eq!(cfg.tls_key_file.as_deref(), Some("server.key"));
+        assert_eq!(cfg.tls_client_ca_file.as_deref(), Some("ca.pem"));
+        assert!(cfg.tls_client_auth_optional);
+        assert_eq!(cfg.tls_client_allowed_sans, vec!["client.example"]);
+        assert_eq!(cfg.tls_min_version.as_deref(), Some("1.3"));
+        assert_eq!(cfg.tls_max_version, None);
+        assert_eq!(
+            cfg.http_headers.get("X-Frame-Options").map(|v| v.as_str()),
+            Some("deny")
+        );
+        assert!(cfg.basic_auth_users.contains_key("alice"));
+    }
+
+    #[test]
+    fn test_web_config_tls_versions() {
+        let cfg = web_config(
+            "versions",
+            "tls_server_config:\n  cert_file: a\n  key_file: b\n  min_version: TLS10\n  max_version: TLS12\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.tls_min_version, None);
+        assert_eq!(cfg.tls_max_version.as_deref(), Some("1.2"));
+
+        assert!(web_config(
+            "conflicting-versions",
+            "tls_server_config:\n  cert_file: a\n  key_file: b\n  min_version: TLS13\n  max_version: TLS12\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_web_config_invalid() {
+        for (name, yaml) in [
+            ("unknown-key", "unknown: true\n"),
+            ("user", "basic_auth_users:\n  'a:b': $2y$04$Zv1W2jWh2KCGgBQ2B6cSqOTvW3HPc9hlAmkU5Xzax9mUTW0Fj3ZSi\n"),
+            ("hash", "basic_auth_users:\n  alice: secret\n"),
+            ("header", "http_server_config:\n  headers:\n    'X Bad': a\n"),
+            ("inline", "tls_server_config:\n  cert: abc\n  key: def\n"),
+            ("key-file", "tls_server_config:\n  cert_file: a\n"),
+            (
+                "client-ca",
+                "tls_server_config:\n  cert_file: a\n  key_file: b\n  client_auth_type: RequireAndVerifyClientCert\n",
+            ),
+            (
+                "client-auth-type",
+                "tls_server_config:\n  cert_file: a\n  key_file: b\n  client_auth_type: Sometimes\n",
+            ),
+            (
+                "allowed-sans",
+                "tls_server_config:\n  cert_file: a\n  key_file: b\n  client_allowed_sans: [a]\n",
+            ),
+        ] {
+            assert!(web_config(name, yaml).is_err(), "{}", name);
+        }
+    }
 }
@@ -8,10 +8,108 @@ pub fn generate_default_user_agent() -> String {
 }
 pub const ROOT_HTML: &str = "<html>\n<head><title>OpenWeatherMap exporter</title></head>\n<body>\n<h1>OpenWeatherMap exporter</h1>\n<p><a href=\"/metrics\">Metrics</a></p>\n</body>\n</html>\n";
 pub const METRICS_PATH: &str = "/metrics";
+pub const INFLUXDB_PATH: &str = "/influx";
+pub const HEALTHY_PATH: &str = "/-/healthy";
+pub const READY_PATH: &str = "/-/ready";
+pub const PROBE_PATH: &str = "/probe";
+pub const SCRAPE_TIMEOUT_HEADER: &str = "x-prometheus-scrape-timeout-seconds";
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const TEXT_FORMAT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_VERSION: &str = "1.0.0";
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+// Units used as suffix of metric names. Humidity and cloud coverage are named percent but
+// exported as ratio and wind speeds are named meters_per_hour but exported in m/s, so these
+// units aren't announced.
+pub const OPENMETRICS_UNITS: [&str; 6] = [
+    "seconds",
+    "bytes",
+    "celsius",
+    "pascal",
+    "degree",
+    "millimeter",
+];
 pub const HTTP_CLIENT_TIMEOUT: u64 = 15;
+pub const MAX_HTTP_CLIENT_TIMEOUT: u64 = 300;
+pub const TLS_VERSIONS: [&str; 2] = ["1.2", "1.3"];
+// Maximal size of the request line and headers received by the HTTP server
+pub const MAX_REQUEST_HEADER_SIZE: usize = 65536;
+// Time a client has to send a complete request to the HTTP server
+pub const HTTP_REQUEST_TIMEOUT: u64 = 15;
+// Each connection is served by a thread of its own
+pub const MAX_HTTP_CONNECTIONS: usize = 64;
+pub const CONFIG_WATCH_INTERVAL: u64 = 5;
+pub const DEFAULT_RETRIES: u32 = 0;
+pub const DEFAULT_RETRY_INITIAL_DELAY: u64 = 1;
+pub const DEFAULT_RETRY_MAX_DELAY: u64 = 30;
+// Only current weather data is requested for each location
+pub const OWM_CALLS_PER_LOCATION: u64 = 1;
+// Keep some of the API call budget for retries
+pub const BUDGET_HEADROOM: f64 = 1.1;
+pub const REFRESH_JITTER_FRACTION: f64 = 0.1;
+pub const FAILED_REFRESH_RETRY_INTERVAL: u64 = 60;
+pub const DEFAULT_BACKGROUND_REFRESH_INTERVAL: u64 = 600;
+pub const BACKGROUND_REFRESH_TICK: u64 = 5;
+// Seconds subtracted from the scrape timeout of Prometheus to send the reply in time
+pub const DEFAULT_SCRAPE_TIMEOUT_OFFSET: f64 = 0.5;
+// Weather data must be at most this old, or twice the refresh interval, to be ready
+pub const DEFAULT_MAX_DATA_AGE: u64 = 3600;
+pub const DEFAULT_PUSHGATEWAY_JOB: &str = "openweathermap";
+pub const DEFAULT_REMOTE_WRITE_MAX_QUEUE: usize = 100;
+pub const REMOTE_WRITE_VERSION: &str = "0.1.0";
+// Delay before queued data is sent again, doubled after each failure
+pub const REMOTE_WRITE_RETRY_INITIAL_DELAY: u64 = 5;
+pub const REMOTE_WRITE_RETRY_MAX_DELAY: u64 = 300;
+pub const DEFAULT_INFLUXDB_MEASUREMENT: &str = "openweathermap";
+pub const DEFAULT_GRAPHITE_PREFIX: &str = "openweathermap";
+pub const DEFAULT_GRAPHITE_TEMPLATE: &str = "{prefix}.{country}.{name}.{metric}";
+pub const OTLP_METRIC_PREFIX: &str = "openweathermap";
+pub const DEFAULT_MQTT_TOPIC_PREFIX: &str = "openweathermap";
+pub const DEFAULT_MQTT_DISCOVERY_PREFIX: &str = "homeassistant";
+// Messages queued for the connection to the MQTT broker, publishing blocks while it is full
+pub const MQTT_QUEUE_SIZE: usize = 64;
 pub const DEFAULT_OWM_UNITS: &str = "metric";
 pub const OWM_URL: &str = "https://api.openweathermap.org/data/2.5/weather";
 
+pub const METRIC_HTTP_RETRIES_NAME: &str = "openweathermap_exporter_http_retries_total";
+pub const METRIC_HTTP_RETRIES_HELP: &str = "Number of retried requests to the OpenWeatherMap API";
+pub const METRIC_BUDGET_REMAINING_NAME: &str = "openweathermap_exporter_api_budget_remaining_calls";
+pub const METRIC_BUDGET_REMAINING_HELP: &str = "Remaining API calls in the current period";
+pub const METRIC_BUDGET_SKIPPED_NAME: &str = "openweathermap_exporter_api_budget_skipped_total";
+pub const METRIC_BUDGET_SKIPPED_HELP: &str =
+    "Number of location updates skipped because the API call budget was exhausted";
+pub const METRIC_SCRAPE_TIMEOUT_SKIPPED_NAME: &str =
+    "openweathermap_exporter_scrape_timeout_skipped_total";
+pub const METRIC_SCRAPE_TIMEOUT_SKIPPED_HELP: &str =
+    "Number of location updates skipped or aborted because the scrape timeout was reached";
+pub const METRIC_REFRESH_INTERVAL_NAME: &str = "openweathermap_exporter_refresh_interval_seconds";
+pub const METRIC_REFRESH_INTERVAL_HELP: &str = "Interval between updates of a location";
+pub const METRIC_UPDATE_FAILURES_NAME: &str = "openweathermap_exporter_update_failures_total";
+pub const METRIC_UPDATE_FAILURES_HELP: &str = "Number of failed updates of weather data";
+pub const METRIC_MISSING_FIELDS_NAME: &str = "openweathermap_exporter_missing_fields_total";
+pub const METRIC_MISSING_FIELDS_HELP: &str =
+    "Number of weather data replies without a value for a field";
+pub const METRIC_CONFIG_RELOAD_SUCCESSFUL_NAME: &str =
+    "openweathermap_exporter_config_last_reload_successful";
+pub const METRIC_CONFIG_RELOAD_SUCCESSFUL_HELP: &str =
+    "Whether the last configuration reload attempt was successful";
+pub const METRIC_CONFIG_RELOAD_SUCCESS_TIMESTAMP_NAME: &str =
+    "openweathermap_exporter_config_last_reload_success_timestamp_seconds";
+pub const METRIC_CONFIG_RELOAD_SUCCESS_TIMESTAMP_HELP: &str =
+    "Timestamp of the last successful configuration reload";
+pub const METRIC_REMOTE_WRITE_QUEUE_NAME: &str =
+    "openweathermap_exporter_remote_write_queue_length";
+pub const METRIC_REMOTE_WRITE_QUEUE_HELP: &str =
+    "Number of batches waiting to be sent to the remote write endpoint";
+pub const METRIC_HTTP_REJECTED_NAME: &str = "openweathermap_exporter_http_requests_rejected_total";
+pub const METRIC_HTTP_REJECTED_HELP: &str =
+    "Number of HTTP connections rejected because the client address is not allowed";
+pub const METRIC_PROBE_SUCCESS_NAME: &str = "openweathermap_probe_success";
+pub const METRIC_PROBE_SUCCESS_HELP: &str =
+    "Whether the weather data of the probed location was fetched";
+pub const METRIC_PROBE_DURATION_NAME: &str = "openweathermap_probe_duration_seconds";
+pub const METRIC_PROBE_DURATION_HELP: &str = "Duration of the probe";
+
 pub const METRIC_TEMP_NAME: &str = "openweathermap_temperature_celsius";
 pub const METRIC_TEMP_HELP: &str = "Temperature";
 pub const METRIC_TEMP_FEELS_LIKE_NAME: &str = "openweathermap_apparent_temperature_celsius";
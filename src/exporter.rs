@@ -1,315 +1,745 @@
+use crate::budget;
 use crate::config;
 use crate::constants;
 use crate::http;
+use crate::openmetrics;
 use crate::openweathermap;
+use crate::schedule;
 
 use lazy_static::lazy_static;
-use log::{debug, error};
-use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
+use log::{debug, error, warn};
+use prometheus::core::{Atomic, Collector, GenericGaugeVec};
+use prometheus::{
+    proto, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
-    pub static ref TEMPERATURE: GaugeVec = GaugeVec::new(
-        Opts::new(constants::METRIC_TEMP_NAME, constants::METRIC_TEMP_HELP),
-        &["name", "country"],
-    )
-    .unwrap();
-    pub static ref TEMPERATURE_FEELS_LIKE: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_TEMP_FEELS_LIKE_NAME,
-            constants::METRIC_TEMP_FEELS_LIKE_HELP
-        ),
-        &["name", "country"],
-    )
-    .unwrap();
-    pub static ref TEMPERATURE_MIN: GaugeVec = GaugeVec::new(
+    // latest weather data of each configured location and the time it was received
+    static ref OBSERVATIONS: Mutex<HashMap<String, (openweathermap::OpenWeatherMap, SystemTime)>> =
+        Mutex::new(HashMap::new());
+    // locations removed by a reload, updates that were started before aren't stored
+    static ref REMOVED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    // update of the weather data in progress
+    static ref IN_FLIGHT: Mutex<Option<Arc<Flight>>> = Mutex::new(None);
+    pub static ref HTTP_RETRIES: IntCounterVec = IntCounterVec::new(
         Opts::new(
-            constants::METRIC_TEMP_MIN_NAME,
-            constants::METRIC_TEMP_MIN_HELP
+            constants::METRIC_HTTP_RETRIES_NAME,
+            constants::METRIC_HTTP_RETRIES_HELP
         ),
-        &["name", "country"],
+        &["reason"],
     )
     .unwrap();
-    pub static ref TEMPERATURE_MAX: GaugeVec = GaugeVec::new(
+    pub static ref BUDGET_REMAINING: IntGaugeVec = IntGaugeVec::new(
         Opts::new(
-            constants::METRIC_TEMP_MAX_NAME,
-            constants::METRIC_TEMP_MAX_HELP
+            constants::METRIC_BUDGET_REMAINING_NAME,
+            constants::METRIC_BUDGET_REMAINING_HELP
         ),
-        &["name", "country"],
+        &["period"],
     )
     .unwrap();
-    pub static ref PRESSURE: IntGaugeVec = IntGaugeVec::new(
-        Opts::new(
-            constants::METRIC_PRESSURE_NAME,
-            constants::METRIC_PRESSURE_HELP
-        ),
-        &["name", "country"],
+    pub static ref BUDGET_SKIPPED: IntCounter = IntCounter::new(
+        constants::METRIC_BUDGET_SKIPPED_NAME,
+        constants::METRIC_BUDGET_SKIPPED_HELP
     )
     .unwrap();
-    pub static ref HUMIDITY: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_HUMIDITY_NAME,
-            constants::METRIC_HUMIDITY_HELP
-        ),
-        &["name", "country"],
+    pub static ref REFRESH_INTERVAL: Gauge = Gauge::new(
+        constants::METRIC_REFRESH_INTERVAL_NAME,
+        constants::METRIC_REFRESH_INTERVAL_HELP
     )
     .unwrap();
-    pub static ref WIND_SPEED: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_WIND_SPEED_NAME,
-            constants::METRIC_WIND_SPEED_HELP,
-        ),
-        &["name", "country"],
+    pub static ref SCRAPE_TIMEOUT_SKIPPED: IntCounter = IntCounter::new(
+        constants::METRIC_SCRAPE_TIMEOUT_SKIPPED_NAME,
+        constants::METRIC_SCRAPE_TIMEOUT_SKIPPED_HELP
     )
     .unwrap();
-    pub static ref WIND_GUST: GaugeVec = GaugeVec::new(
+    pub static ref UPDATE_FAILURES: IntCounterVec = IntCounterVec::new(
         Opts::new(
-            constants::METRIC_WIND_GUST_NAME,
-            constants::METRIC_WIND_GUST_HELP
+            constants::METRIC_UPDATE_FAILURES_NAME,
+            constants::METRIC_UPDATE_FAILURES_HELP
         ),
-        &["name", "country"],
+        &["location", "kind"],
     )
     .unwrap();
-    pub static ref WIND_DIRECTION: IntGaugeVec = IntGaugeVec::new(
+    pub static ref MISSING_FIELDS: IntCounterVec = IntCounterVec::new(
         Opts::new(
-            constants::METRIC_WIND_DIRECTION_NAME,
-            constants::METRIC_WIND_DIRECTION_HELP
+            constants::METRIC_MISSING_FIELDS_NAME,
+            constants::METRIC_MISSING_FIELDS_HELP
         ),
-        &["name", "country"],
+        &["name", "country", "field"],
     )
     .unwrap();
-    pub static ref CLOUD: GaugeVec = GaugeVec::new(
-        Opts::new(constants::METRIC_CLOUD_NAME, constants::METRIC_CLOUD_HELP,),
-        &["name", "country"],
-    )
-    .unwrap();
-    pub static ref RAIN_1H: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_RAIN_1H_NAME,
-            constants::METRIC_RAIN_1H_HELP
-        ),
-        &["name", "country"],
+    pub static ref CONFIG_LAST_RELOAD_SUCCESSFUL: IntGauge = IntGauge::new(
+        constants::METRIC_CONFIG_RELOAD_SUCCESSFUL_NAME,
+        constants::METRIC_CONFIG_RELOAD_SUCCESSFUL_HELP
     )
     .unwrap();
-    pub static ref RAIN_3H: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_RAIN_3H_NAME,
-            constants::METRIC_RAIN_3H_HELP
-        ),
-        &["name", "country"],
+    pub static ref CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP: IntGauge = IntGauge::new(
+        constants::METRIC_CONFIG_RELOAD_SUCCESS_TIMESTAMP_NAME,
+        constants::METRIC_CONFIG_RELOAD_SUCCESS_TIMESTAMP_HELP
     )
     .unwrap();
-    pub static ref SNOW_1H: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_SNOW_1H_NAME,
-            constants::METRIC_SNOW_1H_HELP
-        ),
-        &["name", "country"],
+    pub static ref REMOTE_WRITE_QUEUE: IntGauge = IntGauge::new(
+        constants::METRIC_REMOTE_WRITE_QUEUE_NAME,
+        constants::METRIC_REMOTE_WRITE_QUEUE_HELP
     )
     .unwrap();
-    pub static ref SNOW_3H: GaugeVec = GaugeVec::new(
-        Opts::new(
-            constants::METRIC_SNOW_3H_NAME,
-            constants::METRIC_SNOW_3H_HELP
-        ),
-        &["name", "country"],
+    pub static ref HTTP_REJECTED: IntCounter = IntCounter::new(
+        constants::METRIC_HTTP_REJECTED_NAME,
+        constants::METRIC_HTTP_REJECTED_HELP
     )
     .unwrap();
+    static ref WEATHER: WeatherMetrics = WeatherMetrics::new(true);
+}
+
+// Fields of the weather data that are counted if missing
+const REQUIRED_FIELDS: [&str; 10] = [
+    "sys.country",
+    "main.temp",
+    "main.feels_like",
+    "main.temp_min",
+    "main.temp_max",
+    "main.pressure",
+    "main.humidity",
+    "wind.speed",
+    "wind.deg",
+    "clouds.all",
+];
+
+// Gauges of the weather data, either of all configured locations or of a single probe
+pub struct WeatherMetrics {
+    temperature: GaugeVec,
+    temperature_feels_like: GaugeVec,
+    temperature_min: GaugeVec,
+    temperature_max: GaugeVec,
+    pressure: IntGaugeVec,
+    humidity: GaugeVec,
+    wind_speed: GaugeVec,
+    wind_gust: GaugeVec,
+    wind_direction: IntGaugeVec,
+    cloud: GaugeVec,
+    rain_1h: GaugeVec,
+    rain_3h: GaugeVec,
+    snow_1h: GaugeVec,
+    snow_3h: GaugeVec,
+    // Missing fields are only counted for configured locations
+    count_missing_fields: bool,
+}
+
+fn weather_gauge(name: &str, help: &str) -> GaugeVec {
+    GaugeVec::new(Opts::new(name, help), &["name", "country"]).unwrap()
+}
+
+fn weather_int_gauge(name: &str, help: &str) -> IntGaugeVec {
+    IntGaugeVec::new(Opts::new(name, help), &["name", "country"]).unwrap()
+}
+
+impl WeatherMetrics {
+    pub fn new(count_missing_fields: bool) -> Self {
+        WeatherMetrics {
+            temperature: weather_gauge(constants::METRIC_TEMP_NAME, constants::METRIC_TEMP_HELP),
+            temperature_feels_like: weather_gauge(
+                constants::METRIC_TEMP_FEELS_LIKE_NAME,
+                constants::METRIC_TEMP_FEELS_LIKE_HELP,
+            ),
+            temperature_min: weather_gauge(
+                constants::METRIC_TEMP_MIN_NAME,
+                constants::METRIC_TEMP_MIN_HELP,
+            ),
+            temperature_max: weather_gauge(
+                constants::METRIC_TEMP_MAX_NAME,
+                constants::METRIC_TEMP_MAX_HELP,
+            ),
+            pressure: weather_int_gauge(
+                constants::METRIC_PRESSURE_NAME,
+                constants::METRIC_PRESSURE_HELP,
+            ),
+            humidity: weather_gauge(
+                constants::METRIC_HUMIDITY_NAME,
+                constants::METRIC_HUMIDITY_HELP,
+            ),
+            wind_speed: weather_gauge(
+                constants::METRIC_WIND_SPEED_NAME,
+                constants::METRIC_WIND_SPEED_HELP,
+            ),
+            wind_gust: weather_gauge(
+                constants::METRIC_WIND_GUST_NAME,
+                constants::METRIC_WIND_GUST_HELP,
+            ),
+            wind_direction: weather_int_gauge(
+                constants::METRIC_WIND_DIRECTION_NAME,
+                constants::METRIC_WIND_DIRECTION_HELP,
+            ),
+            cloud: weather_gauge(constants::METRIC_CLOUD_NAME, constants::METRIC_CLOUD_HELP),
+            rain_1h: weather_gauge(
+                constants::METRIC_RAIN_1H_NAME,
+                constants::METRIC_RAIN_1H_HELP,
+            ),
+            rain_3h: weather_gauge(
+                constants::METRIC_RAIN_3H_NAME,
+                constants::METRIC_RAIN_3H_HELP,
+            ),
+            snow_1h: weather_gauge(
+                constants::METRIC_SNOW_1H_NAME,
+                constants::METRIC_SNOW_1H_HELP,
+            ),
+            snow_3h: weather_gauge(
+                constants::METRIC_SNOW_3H_NAME,
+                constants::METRIC_SNOW_3H_HELP,
+            ),
+            count_missing_fields,
+        }
+    }
+
+    fn gauges(&self) -> [&GaugeVec; 12] {
+        [
+            &self.temperature,
+            &self.temperature_feels_like,
+            &self.temperature_min,
+            &self.temperature_max,
+            &self.humidity,
+            &self.wind_speed,
+            &self.wind_gust,
+            &self.cloud,
+            &self.rain_1h,
+            &self.rain_3h,
+            &self.snow_1h,
+            &self.snow_3h,
+        ]
+    }
+
+    fn int_gauges(&self) -> [&IntGaugeVec; 2] {
+        [&self.pressure, &self.wind_direction]
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        for gauge in self.gauges() {
+            registry.register(Box::new(gauge.clone()))?;
+        }
+        for gauge in self.int_gauges() {
+            registry.register(Box::new(gauge.clone()))?;
+        }
+        Ok(())
+    }
+
+    // Metric families with at least one location, sorted by name as by Registry::gather
+    pub fn gather(&self) -> Vec<proto::MetricFamily> {
+        let mut result: Vec<proto::MetricFamily> = self
+            .gauges()
+            .iter()
+            .flat_map(|v| v.collect())
+            .chain(self.int_gauges().iter().flat_map(|v| v.collect()))
+            .filter(|v| !v.get_metric().is_empty())
+            .collect();
+        result.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        result
+    }
+
+    pub fn set(&self, data: &openweathermap::OpenWeatherMap) {
+        let labels = [data.name.as_str(), data.country()];
+
+        if data.sys.as_ref().and_then(|v| v.country.as_ref()).is_none() {
+            self.missing_field(&labels, "sys.country");
+        }
+
+        self.set_value(&self.temperature, &labels, "main.temp", data.main.temp);
+        self.set_value(
+            &self.temperature_feels_like,
+            &labels,
+            "main.feels_like",
+            data.main.feels_like,
+        );
+        self.set_value(
+            &self.temperature_min,
+            &labels,
+            "main.temp_min",
+            data.main.temp_min,
+        );
+        self.set_value(
+            &self.temperature_max,
+            &labels,
+            "main.temp_max",
+            data.main.temp_max,
+        );
+        self.set_value(
+            &self.pressure,
+            &labels,
+            "main.pressure",
+            data.main.pressure.map(|v| 100 * v as i64),
+        );
+        self.set_value(
+            &self.humidity,
+            &labels,
+            "main.humidity",
+            data.main.humidity.map(|v| v as f64 / 100.0),
+        );
+        self.set_value(&self.wind_speed, &labels, "wind.speed", data.wind.speed);
+        self.set_value(
+            &self.wind_direction,
+            &labels,
+            "wind.deg",
+            data.wind.deg.map(|v| v as i64),
+        );
+        self.set_value(
+            &self.cloud,
+            &labels,
+            "clouds.all",
+            data.clouds
+                .as_ref()
+                .and_then(|v| v.all)
+                .map(|v| v as f64 / 100.0),
+        );
+
+        // Wind gusts, rain and snow are only reported if present
+        if let Some(gust) = data.wind.gust {
+            self.set_value(&self.wind_gust, &labels, "wind.gust", Some(gust));
+        }
+
+        if let Some(rain) = &data.rain {
+            if let Some(one_h) = rain.one_h {
+                self.set_value(&self.rain_1h, &labels, "rain.1h", Some(one_h));
+            }
+            if let Some(three_h) = rain.three_h {
+                self.set_value(&self.rain_3h, &labels, "rain.3h", Some(three_h));
+            }
+        }
+
+        if let Some(snow) = &data.snow {
+            if let Some(one_h) = snow.one_h {
+                self.set_value(&self.snow_1h, &labels, "snow.1h", Some(one_h));
+            }
+            if let Some(three_h) = snow.three_h {
+                self.set_value(&self.snow_3h, &labels, "snow.3h", Some(three_h));
+            }
+        }
+    }
+
+    fn remove(&self, name: &str, country: &str) {
+        let labels = [name, country];
+
+        for gauge in self.gauges() {
+            let _ = gauge.remove_label_values(&labels);
+        }
+        for gauge in self.int_gauges() {
+            let _ = gauge.remove_label_values(&labels);
+        }
+        if self.count_missing_fields {
+            for field in REQUIRED_FIELDS {
+                let _ = MISSING_FIELDS.remove_label_values(&[name, country, field]);
+            }
+        }
+    }
+
+    fn set_value<P: Atomic>(
+        &self,
+        gauge: &GenericGaugeVec<P>,
+        labels: &[&str],
+        field: &str,
+        value: Option<P::T>,
+    ) where
+        P::T: std::fmt::Display,
+    {
+        match value {
+            Some(v) => {
+                debug!(
+                    "Setting {} {} -> {}",
+                    gauge.desc()[0].fq_name,
+                    labels.join(" "),
+                    v
+                );
+                gauge.with_label_values(labels).set(v);
+            }
+            None => {
+                // Don't export outdated values
+                let _ = gauge.remove_label_values(labels);
+                self.missing_field(labels, field);
+            }
+        }
+    }
+
+    fn missing_field(&self, labels: &[&str], field: &str) {
+        debug!("Field {} is missing for {}", field, labels.join(" "));
+        if self.count_missing_fields {
+            MISSING_FIELDS
+                .with_label_values(&[labels[0], labels[1], field])
+                .inc();
+        }
+    }
 }
 
 pub fn register() {
+    WEATHER.register(&REGISTRY).unwrap();
+    REGISTRY.register(Box::new(HTTP_RETRIES.clone())).unwrap();
+    REGISTRY
+        .register(Box::new(BUDGET_REMAINING.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(BUDGET_SKIPPED.clone())).unwrap();
+    REGISTRY
+        .register(Box::new(SCRAPE_TIMEOUT_SKIPPED.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(REFRESH_INTERVAL.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(UPDATE_FAILURES.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(MISSING_FIELDS.clone())).unwrap();
     REGISTRY
-        .register(Box::new(TEMPERATURE_FEELS_LIKE.clone()))
+        .register(Box::new(CONFIG_LAST_RELOAD_SUCCESSFUL.clone()))
         .unwrap();
     REGISTRY
-        .register(Box::new(TEMPERATURE_MIN.clone()))
+        .register(Box::new(CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP.clone()))
         .unwrap();
     REGISTRY
-        .register(Box::new(TEMPERATURE_MAX.clone()))
+        .register(Box::new(REMOTE_WRITE_QUEUE.clone()))
         .unwrap();
-    REGISTRY.register(Box::new(TEMPERATURE.clone())).unwrap();
-    REGISTRY.register(Box::new(PRESSURE.clone())).unwrap();
-    REGISTRY.register(Box::new(HUMIDITY.clone())).unwrap();
-    REGISTRY.register(Box::new(WIND_SPEED.clone())).unwrap();
-    REGISTRY.register(Box::new(WIND_GUST.clone())).unwrap();
-    REGISTRY.register(Box::new(WIND_DIRECTION.clone())).unwrap();
-    REGISTRY.register(Box::new(CLOUD.clone())).unwrap();
-    REGISTRY.register(Box::new(RAIN_1H.clone())).unwrap();
-    REGISTRY.register(Box::new(RAIN_3H.clone())).unwrap();
-    REGISTRY.register(Box::new(SNOW_1H.clone())).unwrap();
-    REGISTRY.register(Box::new(SNOW_3H.clone())).unwrap();
+    REGISTRY.register(Box::new(HTTP_REJECTED.clone())).unwrap();
 }
 
-fn update_metrics(cfg: &config::Configuration) {
-    let cfg = cfg.clone();
-    let timeout = cfg.timeout.unwrap_or(constants::HTTP_CLIENT_TIMEOUT);
-    let mut client = match http::build_client(timeout) {
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UpdateSummary {
+    pub updated: usize,
+    pub failed: usize,
+    // Locations that were skipped or aborted at the deadline
+    pub skipped: usize,
+}
+
+// Update in progress, concurrent callers wait for it to finish and share its summary
+struct Flight {
+    summary: Mutex<Option<UpdateSummary>>,
+    finished: Condvar,
+    // The update runs until the latest deadline of all callers, without a deadline if
+    // any caller has none
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl Flight {
+    fn new(deadline: Option<Instant>) -> Self {
+        Flight {
+            summary: Mutex::new(None),
+            finished: Condvar::new(),
+            deadline: Mutex::new(deadline),
+        }
+    }
+
+    fn join(&self, deadline: Option<Instant>) {
+        let mut current = self.deadline.lock().unwrap();
+        *current = match (*current, deadline) {
+            (Some(current), Some(deadline)) => Some(current.max(deadline)),
+            _ => None,
+        };
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap()
+    }
+
+    // Returns None if the deadline is reached before the update is finished
+    fn wait(&self, deadline: Option<Instant>) -> Option<UpdateSummary> {
+        let mut summary = self.summary.lock().unwrap();
+        loop {
+            if let Some(v) = *summary {
+                return Some(v);
+            }
+            summary = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return None;
+                    }
+                    self.finished.wait_timeout(summary, remaining).unwrap().0
+                }
+                None => self.finished.wait(summary).unwrap(),
+            };
+        }
+    }
+}
+
+// Finishes the update even if it panics, otherwise waiting callers would block forever
+struct FlightGuard(Arc<Flight>);
+
+impl FlightGuard {
+    fn finish(self, summary: UpdateSummary) {
+        *self.0.summary.lock().unwrap() = Some(summary);
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        *IN_FLIGHT.lock().unwrap() = None;
+        self.0
+            .summary
+            .lock()
+            .unwrap()
+            .get_or_insert_with(UpdateSummary::default);
+        self.0.finished.notify_all();
+    }
+}
+
+// Concurrent scrapes (e.g. of two Prometheus servers) and background refreshes would query
+// the API for every location twice and race on the gauges, so only one update runs at a time.
+// The update runs in a thread of its own, so every caller can stop waiting at its deadline.
+fn update_metrics_once(cfg: &config::Configuration, deadline: Option<Instant>) -> UpdateSummary {
+    loop {
+        let flight = {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            match &*in_flight {
+                Some(v) => {
+                    debug!("Update of weather data in progress, waiting for it to finish");
+                    v.join(deadline);
+                    v.clone()
+                }
+                None => {
+                    let v = Arc::new(Flight::new(deadline));
+                    *in_flight = Some(v.clone());
+                    let guard = FlightGuard(v.clone());
+                    let cfg = cfg.clone();
+                    thread::spawn(move || {
+                        let summary = update_metrics(&cfg, || guard.0.deadline());
+                        guard.finish(summary);
+                    });
+                    v
+                }
+            }
+        };
+
+        let summary = match flight.wait(deadline) {
+            Some(v) => v,
+            None => {
+                warn!("Scrape timeout reached while waiting for the update of weather data, using previous values");
+                return UpdateSummary::default();
+            }
+        };
+        // Locations skipped at the earlier deadline of another caller are still due
+        let time_left = match deadline {
+            Some(v) => Instant::now() < v,
+            None => true,
+        };
+        if summary.skipped > 0 && time_left {
+            debug!("Update of weather data was cut short, updating skipped locations");
+            continue;
+        }
+        return summary;
+    }
+}
+
+fn update_metrics<F>(cfg: &config::Configuration, deadline: F) -> UpdateSummary
+where
+    F: Fn() -> Option<Instant>,
+{
+    let mut summary = UpdateSummary::default();
+    let mut client = match http::build_api_client(cfg) {
         Ok(v) => v,
         Err(e) => panic!("Can't build HTTP client structure: {}", e),
     };
-    for location in cfg.locations {
-        let url = format!(
-            "{}?q={}&units={}&APPID={}",
-            constants::OWM_URL,
-            location,
-            constants::DEFAULT_OWM_UNITS,
-            cfg.api_key
-        );
+    for location_cfg in &cfg.locations {
+        let location = &location_cfg.to_string();
+        if !schedule::is_due(location) {
+            debug!(
+                "Weather data for {} is still current, skipping update",
+                location
+            );
+            continue;
+        }
+
+        // Locations that are skipped stay due and keep their previous values, the deadline
+        // can be extended by callers joining the update
+        let deadline = deadline();
+        if deadline.is_some_and(|v| Instant::now() >= v) {
+            warn!(
+                "Scrape timeout reached, skipping update of weather data for {}",
+                location
+            );
+            SCRAPE_TIMEOUT_SKIPPED.inc();
+            summary.skipped += 1;
+            continue;
+        }
+
+        if !budget::acquire() {
+            warn!(
+                "API call budget exhausted, skipping update of weather data for {}",
+                location
+            );
+            BUDGET_SKIPPED.inc();
+            summary.failed += 1;
+            continue;
+        }
 
-        debug!("Requesting data from {}", url);
-        let reply = match http::get(&mut client, &url) {
+        let data = match fetch_location(&mut client, cfg, location_cfg, deadline) {
             Ok(v) => v,
-            Err(e) => {
-                error!("Can't fetch weather data for {}: {}", location, e);
+            // Requests aborted at the deadline aren't failures of the location, it stays due
+            Err(e)
+                if error_kind(e.as_ref()) == "timeout"
+                    && deadline.is_some_and(|v| Instant::now() >= v) =>
+            {
+                warn!(
+                    "Scrape timeout reached, aborted update of weather data for {}",
+                    location
+                );
+                SCRAPE_TIMEOUT_SKIPPED.inc();
+                summary.skipped += 1;
                 continue;
             }
-        };
-
-        let data: openweathermap::OpenWeatherMap = match serde_json::from_str(&reply) {
-            Ok(v) => v,
             Err(e) => {
-                error!("Can't parse result for {} as JSON: {}", location, e);
+                schedule::failed(location);
+                error!("Can't update weather data for {}: {}", location, e);
+                count_update_failure(location, error_kind(e.as_ref()));
+                summary.failed += 1;
                 continue;
             }
         };
-        debug!(
-            "Setting openweathermap_temperature_celsius {} {} -> {}",
-            data.name, data.sys.country, data.main.temp
-        );
-        TEMPERATURE
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.main.temp);
 
-        debug!(
-            "Setting openweathermap_apparent_temperature_celsius {} {} -> {}",
-            data.name, data.sys.country, data.main.feels_like
-        );
-        TEMPERATURE_FEELS_LIKE
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.main.feels_like);
-
-        debug!(
-            "Setting openweathermap_minimal_temperature_celsius {} {} -> {}",
-            data.name, data.sys.country, data.main.temp_min
-        );
-        TEMPERATURE_MIN
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.main.temp_min);
+        schedule::done(location);
+        set_location_metrics(location, &data);
+        summary.updated += 1;
+    }
+    summary
+}
 
-        debug!(
-            "Setting openweathermap_maximal_temperature_celsius {} {} -> {}",
-            data.name, data.sys.country, data.main.temp_max
-        );
-        TEMPERATURE_MAX
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.main.temp_max);
+// Fetch current weather data of a location, the caller must account for the API call budget
+pub fn fetch_location(
+    client: &mut reqwest::blocking::Client,
+    cfg: &config::Configuration,
+    location: &config::Location,
+    deadline: Option<Instant>,
+) -> Result<openweathermap::OpenWeatherMap, Box<dyn Error>> {
+    let retry_cfg = cfg.retry.clone().unwrap_or_default();
+    let mut query = location.query();
+    query.push(("units", constants::DEFAULT_OWM_UNITS.to_string()));
+    query.push(("APPID", cfg.api_key.clone()));
+    let url = reqwest::Url::parse_with_params(constants::OWM_URL, &query)?.to_string();
 
-        debug!(
-            "Setting openweathermap_pressure_pascal {} {} -> {}",
-            data.name,
-            data.sys.country,
-            100 * data.main.pressure
-        );
-        PRESSURE
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(100 * data.main.pressure as i64);
+    debug!("Requesting data from {}", url);
+    let timeout = Duration::from_secs(cfg.timeout.unwrap_or(constants::HTTP_CLIENT_TIMEOUT));
+    let reply = http::get(client, &url, &retry_cfg, timeout, deadline)?;
+    let data: openweathermap::OpenWeatherMap = serde_json::from_str(&reply)?;
+    Ok(data)
+}
 
-        debug!(
-            "Setting openweathermap_humidity_percent {} {} -> {}",
-            data.name,
-            data.sys.country,
-            data.main.humidity as f64 / 100.0
-        );
-        HUMIDITY
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.main.humidity as f64 / 100.0);
+pub fn error_kind(e: &(dyn Error + 'static)) -> &'static str {
+    if let Some(v) = e.downcast_ref::<openweathermap::ApiError>() {
+        v.kind.as_str()
+    } else if e.is::<serde_json::Error>() {
+        "parse"
+    } else {
+        "other"
+    }
+}
 
+pub fn set_location_metrics(location: &str, data: &openweathermap::OpenWeatherMap) {
+    // An update with the previous configuration may still be running after a reload
+    let removed = REMOVED.lock().unwrap();
+    if removed.contains(location) {
         debug!(
-            "Setting openweathermap_wind_speed_kilometers_per_hour {} {} -> {}",
-            data.name, data.sys.country, data.wind.speed
+            "Location {} was removed, discarding its weather data",
+            location
         );
-        WIND_SPEED
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.wind.speed);
+        return;
+    }
 
-        if let Some(gust) = data.wind.gust {
-            debug!(
-                "Setting openweathermap_wind_gust_speed_kilometers_per_hour {} {} -> {}",
-                data.name, data.sys.country, gust
-            );
-            WIND_GUST
-                .with_label_values(&[&data.name, &data.sys.country])
-                .set(gust);
+    let previous = OBSERVATIONS
+        .lock()
+        .unwrap()
+        .insert(location.to_string(), (data.clone(), SystemTime::now()));
+    if let Some((previous, _)) = previous {
+        if previous.name != data.name || previous.country() != data.country() {
+            WEATHER.remove(&previous.name, previous.country());
         }
+    }
 
+    WEATHER.set(data);
+}
+
+// Remove all weather data and counters of a location that is no longer configured
+pub fn remove_location(location: &str) {
+    let mut removed = REMOVED.lock().unwrap();
+    removed.insert(location.to_string());
+
+    let kinds = openweathermap::ApiErrorKind::ALL.map(|v| v.as_str());
+    for kind in kinds.iter().chain(&["parse", "other"]) {
+        let _ = UPDATE_FAILURES.remove_label_values(&[location, kind]);
+    }
+    if let Some((data, _)) = OBSERVATIONS.lock().unwrap().remove(location) {
         debug!(
-            "Setting openweathermap_wind_direction_degree {} {} -> {}",
-            data.name, data.sys.country, data.wind.deg
-        );
-        WIND_DIRECTION
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.wind.deg as i64);
-        debug!(
-            "Setting openweathermap_cloud_coverage_percent {} {} -> {}",
+            "Removing weather data of {} ({} {})",
+            location,
             data.name,
-            data.sys.country,
-            data.clouds.all as f64 / 100.0
+            data.country()
         );
-        CLOUD
-            .with_label_values(&[&data.name, &data.sys.country])
-            .set(data.clouds.all as f64 / 100.0);
+        WEATHER.remove(&data.name, data.country());
+    }
+}
 
-        if let Some(rain) = data.rain {
-            if let Some(one_h) = rain.one_h {
-                debug!(
-                    "Setting openweathermap_rain_precipation_last_hour_millimeter {} {} -> {}",
-                    data.name, data.sys.country, one_h
-                );
-                RAIN_1H
-                    .with_label_values(&[&data.name, &data.sys.country])
-                    .set(one_h);
-            }
-            if let Some(three_h) = rain.three_h {
-                debug!(
-                    "Setting openweathermap_rain_precipation_last_three_hours_millimeter {} {} -> {}",
-                    data.name,
-                    data.sys.country,
-                    three_h
-                );
-                RAIN_3H
-                    .with_label_values(&[&data.name, &data.sys.country])
-                    .set(three_h);
-            }
-        }
+fn count_update_failure(location: &str, kind: &str) {
+    let removed = REMOVED.lock().unwrap();
+    if !removed.contains(location) {
+        UPDATE_FAILURES.with_label_values(&[location, kind]).inc();
+    }
+}
 
-        if let Some(snow) = data.snow {
-            if let Some(one_h) = snow.one_h {
-                debug!(
-                    "Setting openweathermap_snow_precipation_last_hour_millimeter {} {} -> {}",
-                    data.name, data.sys.country, one_h
-                );
-                SNOW_1H
-                    .with_label_values(&[&data.name, &data.sys.country])
-                    .set(one_h);
-            }
-            if let Some(three_h) = snow.three_h {
-                debug!(
-                    "Setting openweathermap_snow_precipation_last_three_hours_millimeter {} {} -> {}",
-                    data.name,
-                    data.sys.country,
-                    three_h
-                );
-                SNOW_3H
-                    .with_label_values(&[&data.name, &data.sys.country])
-                    .set(three_h);
-            }
-        }
+// Locations added by a reload may have been removed before
+pub fn add_location(location: &str) {
+    REMOVED.lock().unwrap().remove(location);
+}
+
+// Weather metrics of all configured locations, without the metrics of the exporter itself
+pub fn gather_weather() -> Vec<proto::MetricFamily> {
+    WEATHER.gather()
+}
+
+// Latest weather data of all locations, sorted by configured location
+pub fn observations() -> Vec<(String, openweathermap::OpenWeatherMap)> {
+    let mut result: Vec<_> = OBSERVATIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, (data, _))| (k.clone(), data.clone()))
+        .collect();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+// Time of the last successful update of all locations, sorted by configured location
+pub fn last_updates() -> Vec<(String, SystemTime)> {
+    let mut result: Vec<_> = OBSERVATIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, (_, updated))| (k.clone(), *updated))
+        .collect();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+// The deadline is derived from the scrape timeout of Prometheus
+pub fn serve_metrics(
+    cfg: &config::Configuration,
+    format: openmetrics::Format,
+    deadline: Option<Instant>,
+) -> String {
+    update_metrics_once(cfg, deadline);
+    budget::update_metrics();
+    match format {
+        openmetrics::Format::Text => encode_metrics(true),
+        openmetrics::Format::OpenMetrics => encode_openmetrics(true),
     }
 }
 
-pub fn serve_metrics(cfg: &config::Configuration) -> String {
-    update_metrics(cfg);
+// Update all locations that are due without encoding the metrics, e.g. for one-shot mode
+// or the background refresh of push outputs
+pub fn refresh(cfg: &config::Configuration) -> UpdateSummary {
+    let summary = update_metrics_once(cfg, None);
+    budget::update_metrics();
+    summary
+}
 
+// Process metrics are only useful for the long running exporter
+pub fn encode_metrics(include_process_metrics: bool) -> String {
     let encoder = prometheus::TextEncoder::new();
     let mut buffer = String::new();
 
@@ -317,8 +747,177 @@ pub fn serve_metrics(cfg: &config::Configuration) -> String {
         error!("Can't encode metrics as UTF8 string: {}", e);
     }
 
-    if let Err(e) = encoder.encode_utf8(&prometheus::gather(), &mut buffer) {
-        error!("Can't encode metrics as UTF8 string: {}", e);
-    };
+    if include_process_metrics {
+        if let Err(e) = encoder.encode_utf8(&prometheus::gather(), &mut buffer) {
+            error!("Can't encode metrics as UTF8 string: {}", e);
+        };
+    }
+    buffer
+}
+
+pub fn encode_openmetrics(include_process_metrics: bool) -> String {
+    let mut buffer = String::new();
+
+    openmetrics::encode(&REGISTRY.gather(), &mut buffer);
+    if include_process_metrics {
+        openmetrics::encode(&prometheus::gather(), &mut buffer);
+    }
+    buffer.push_str("# EOF\n");
     buffer
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Label values of all series of the given metric families with the given label
+    fn label_values(families: &[proto::MetricFamily], label: &str) -> Vec<String> {
+        families
+            .iter()
+            .flat_map(|mf| mf.get_metric())
+            .flat_map(|m| m.get_label())
+            .filter(|l| l.get_name() == label)
+            .map(|l| l.get_value().to_string())
+            .collect()
+    }
+
+    // Proxy for the API requests that counts them and fails each after the given delay
+    fn slow_proxy(delay: Duration) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                thread::spawn(move || {
+                    let _ = stream.read(&mut [0; 4096]);
+                    thread::sleep(delay);
+                    let _ =
+                        stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n");
+                });
+            }
+        });
+        let cfg = format!(
+            "retry: {{retries: 0}}\nproxy: {{url: 'http://127.0.0.1:{}'}}\n",
+            port
+        );
+        (cfg, requests)
+    }
+
+    #[test]
+    fn test_update_metrics_once() {
+        let (proxy, requests) = slow_proxy(Duration::from_millis(500));
+        let cfg =
+            config::test_configuration(&format!("locations: [Single Flight Test]\n{}", proxy));
+
+        // The second caller joins the update of the first one
+        let first = {
+            let cfg = cfg.clone();
+            thread::spawn(move || update_metrics_once(&cfg, None))
+        };
+        thread::sleep(Duration::from_millis(100));
+        let second = update_metrics_once(&cfg, None);
+        let first = first.join().unwrap();
+
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!((first.updated, first.failed, first.skipped), (0, 1, 0));
+        assert_eq!((second.updated, second.failed, second.skipped), (0, 1, 0));
+    }
+
+    #[test]
+    fn test_update_metrics_once_deadline() {
+        let (proxy, requests) = slow_proxy(Duration::from_millis(2000));
+        let cfg = config::test_configuration(&format!("locations: [Deadline Test]\n{}", proxy));
+
+        let first = {
+            let cfg = cfg.clone();
+            let deadline = Instant::now() + Duration::from_millis(1000);
+            thread::spawn(move || (update_metrics_once(&cfg, Some(deadline)), Instant::now()))
+        };
+        while requests.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The second caller has no deadline, so the update runs until all locations are done
+        let second = update_metrics_once(&cfg, None);
+        let (first, first_returned) = first.join().unwrap();
+
+        // The first caller stops waiting at its deadline. The request that was started with
+        // it is aborted and repeated for the second caller.
+        assert!(first_returned < Instant::now() - Duration::from_millis(1000));
+        assert_eq!((first.updated, first.failed), (0, 0));
+        assert_eq!((second.updated, second.failed, second.skipped), (0, 1, 0));
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_remove_location() {
+        // Without temperature, the missing field is counted
+        let data = serde_json::from_str(
+            r#"{"name": "Removed Test", "sys": {"country": "DE"}, "main": {"humidity": 40}}"#,
+        )
+        .unwrap();
+        set_location_metrics("removed test", &data);
+        UPDATE_FAILURES
+            .with_label_values(&["removed test", "timeout"])
+            .inc();
+        assert!(label_values(&gather_weather(), "name").contains(&"Removed Test".to_string()));
+        assert!(
+            label_values(&MISSING_FIELDS.collect(), "name").contains(&"Removed Test".to_string())
+        );
+
+        remove_location("removed test");
+        assert!(!label_values(&gather_weather(), "name").contains(&"Removed Test".to_string()));
+        assert!(
+            !label_values(&MISSING_FIELDS.collect(), "name").contains(&"Removed Test".to_string())
+        );
+        assert!(!label_values(&UPDATE_FAILURES.collect(), "location")
+            .contains(&"removed test".to_string()));
+        assert!(observations().iter().all(|(k, _)| k != "removed test"));
+    }
+
+    #[test]
+    fn test_set_location_metrics_renamed() {
+        let data =
+            serde_json::from_str(r#"{"name": "Renamed Test", "main": {"temp": 1.0}}"#).unwrap();
+        set_location_metrics("renamed test", &data);
+        let data =
+            serde_json::from_str(r#"{"name": "Renamed Test 2", "main": {"temp": 2.0}}"#).unwrap();
+        set_location_metrics("renamed test", &data);
+
+        let names = label_values(&gather_weather(), "name");
+        assert!(!names.contains(&"Renamed Test".to_string()));
+        assert!(names.contains(&"Renamed Test 2".to_string()));
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let e: Box<dyn Error> = Box::new(openweathermap::ApiError::from_response(404, ""));
+        assert_eq!(error_kind(e.as_ref()), "unknown_location");
+        let e: Box<dyn Error> =
+            Box::new(serde_json::from_str::<openweathermap::OpenWeatherMap>("{}").unwrap_err());
+        assert_eq!(error_kind(e.as_ref()), "parse");
+        let e: Box<dyn Error> = "failed".into();
+        assert_eq!(error_kind(e.as_ref()), "other");
+    }
+
+    #[test]
+    fn test_removed_location_not_added_again() {
+        let data: openweathermap::OpenWeatherMap =
+            serde_json::from_str(r#"{"name": "Readded Test", "main": {"temp": 1.0}}"#).unwrap();
+        remove_location("readded test");
+
+        // Updates started before the reload
+        set_location_metrics("readded test", &data);
+        count_update_failure("readded test", "timeout");
+        assert!(!label_values(&gather_weather(), "name").contains(&"Readded Test".to_string()));
+        assert!(!label_values(&UPDATE_FAILURES.collect(), "location")
+            .contains(&"readded test".to_string()));
+
+        add_location("readded test");
+        set_location_metrics("readded test", &data);
+        assert!(label_values(&gather_weather(), "name").contains(&"Readded Test".to_string()));
+    }
+}
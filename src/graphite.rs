@@ -0,0 +1,176 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::error::Error;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+lazy_static! {
+    // Keep the connection to the carbon server open between updates, the configured address
+    // is kept to reconnect if it was changed by a reload
+    static ref CONNECTION: Mutex<Option<(String, TcpStream)>> = Mutex::new(None);
+}
+
+// Dots separate path components, so replace them and any other special characters
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn encode(graphite_cfg: &config::GraphiteConfiguration) -> String {
+    let template = graphite_cfg
+        .template
+        .as_deref()
+        .unwrap_or(constants::DEFAULT_GRAPHITE_TEMPLATE);
+    let prefix = graphite_cfg
+        .prefix
+        .as_deref()
+        .unwrap_or(constants::DEFAULT_GRAPHITE_PREFIX);
+    let now = chrono::Utc::now().timestamp();
+    let mut result = String::new();
+
+    for (location, data) in exporter::observations() {
+        let timestamp = data.dt.unwrap_or(now);
+        let path = template
+            .replace("{prefix}", prefix)
+            .replace("{location}", &sanitize(&location))
+            .replace("{country}", &sanitize(data.country()))
+            .replace("{name}", &sanitize(&data.name))
+            .replace("{id}", &data.id.map(|v| v.to_string()).unwrap_or_default());
+
+        for (metric, value) in data.values() {
+            result.push_str(&format!(
+                "{} {} {}\n",
+                path.replace("{metric}", metric),
+                value,
+                timestamp
+            ));
+        }
+    }
+    result
+}
+
+fn connect(
+    graphite_cfg: &config::GraphiteConfiguration,
+    timeout: Duration,
+) -> Result<TcpStream, Box<dyn Error>> {
+    let mut last_error = None;
+    for addr in graphite_cfg.address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                info!("Connected to carbon server {}", addr);
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => bail!("can't connect to {}: {}", graphite_cfg.address, e),
+        None => bail!("can't resolve {}", graphite_cfg.address),
+    }
+}
+
+pub fn send(
+    cfg: &config::Configuration,
+    graphite_cfg: &config::GraphiteConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let data = encode(graphite_cfg);
+    if data.is_empty() {
+        debug!("No weather data to send to carbon server");
+        return Ok(());
+    }
+
+    let timeout = Duration::from_secs(cfg.timeout.unwrap_or(constants::HTTP_CLIENT_TIMEOUT));
+    let mut connection = CONNECTION.lock().unwrap();
+    if connection
+        .as_ref()
+        .is_some_and(|(address, _)| *address != graphite_cfg.address)
+    {
+        info!("Address of carbon server has changed, reconnecting");
+        *connection = None;
+    }
+
+    // A broken connection is often only noticed on write, so reconnect and try once more
+    for attempt in 0..2 {
+        let (_, stream) = match connection.as_mut() {
+            Some(v) => v,
+            None => connection.insert((
+                graphite_cfg.address.clone(),
+                connect(graphite_cfg, timeout)?,
+            )),
+        };
+
+        match stream
+            .write_all(data.as_bytes())
+            .and_then(|_| stream.flush())
+        {
+            Ok(_) => {
+                debug!("Sent {} lines to carbon server", data.lines().count());
+                return Ok(());
+            }
+            Err(e) => {
+                *connection = None;
+                if attempt > 0 {
+                    bail!("can't send data to {}: {}", graphite_cfg.address, e);
+                }
+                warn!(
+                    "Connection to carbon server {} failed, reconnecting: {}",
+                    graphite_cfg.address, e
+                );
+            }
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("Frankfurt am Main"), "Frankfurt_am_Main");
+        assert_eq!(sanitize("St. Gallen,ch"), "St__Gallen_ch");
+        assert_eq!(sanitize("Zürich-Nord_1"), "Zürich-Nord_1");
+    }
+
+    #[test]
+    fn test_encode() {
+        let data = serde_json::from_str(
+            r#"{"dt": 1700000000, "id": 2, "name": "Graphite Test", "sys": {"country": "DE"},
+                "main": {"temp": 21.5}}"#,
+        )
+        .unwrap();
+        exporter::set_location_metrics("graphite.test", &data);
+
+        let graphite_cfg: config::GraphiteConfiguration = serde_yaml::from_str(
+            "address: localhost:2003\ntemplate: '{prefix}.{location}.{id}.{metric}'",
+        )
+        .unwrap();
+        let result = encode(&graphite_cfg);
+        assert!(result
+            .lines()
+            .any(|v| v == "openweathermap.graphite_test.2.temperature 21.5 1700000000"));
+
+        let graphite_cfg: config::GraphiteConfiguration =
+            serde_yaml::from_str("address: localhost:2003\nprefix: weather").unwrap();
+        let result = encode(&graphite_cfg);
+        assert!(result
+            .lines()
+            .any(|v| v == "weather.DE.Graphite_Test.temperature 21.5 1700000000"));
+    }
+}
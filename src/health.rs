@@ -0,0 +1,114 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::schedule;
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+// The process is alive if it is able to answer
+pub fn healthy() -> String {
+    json!({ "status": "healthy" }).to_string()
+}
+
+// Weather data is considered stale after twice the refresh interval, but not before the
+// default, because scrapes only update locations that are due
+fn max_data_age(cfg: &config::Configuration) -> Duration {
+    match cfg.max_data_age {
+        Some(v) => Duration::from_secs(v),
+        None => {
+            let default = Duration::from_secs(constants::DEFAULT_MAX_DATA_AGE);
+            match schedule::interval() {
+                Some(v) => default.max(v * 2),
+                None => default,
+            }
+        }
+    }
+}
+
+// Ready if at least one location has been updated within the maximal data age,
+// returns the readiness and the status as JSON, with the details of every location if requested
+pub fn ready(cfg: &config::Configuration, details: bool) -> (bool, String) {
+    let max_age = max_data_age(cfg);
+    let now = SystemTime::now();
+    let last_updates: HashMap<String, SystemTime> = exporter::last_updates().into_iter().collect();
+
+    let mut ready = false;
+    let mut locations = Vec::new();
+    for location_cfg in &cfg.locations {
+        let location = location_cfg.to_string();
+        let details = match last_updates.get(&location) {
+            Some(last_update) => {
+                let age = now.duration_since(*last_update).unwrap_or_default();
+                let current = age <= max_age;
+                ready |= current;
+                json!({
+                    "location": location,
+                    "last_update": chrono::DateTime::<chrono::Utc>::from(*last_update).to_rfc3339(),
+                    "age_seconds": age.as_secs(),
+                    "current": current,
+                })
+            }
+            None => json!({
+                "location": location,
+                "last_update": null,
+                "age_seconds": null,
+                "current": false,
+            }),
+        };
+        locations.push(details);
+    }
+
+    let status = if ready { "ready" } else { "not ready" };
+    let body = if details {
+        json!({
+            "status": status,
+            "max_data_age_seconds": max_age.as_secs(),
+            "locations": locations,
+        })
+    } else {
+        json!({ "status": status })
+    };
+    (ready, body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_data_age() {
+        assert_eq!(
+            max_data_age(&config::test_configuration(
+                "locations: [Berlin]\nmax_data_age: 60"
+            )),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn test_ready() {
+        let cfg = config::test_configuration("locations: [Health Test Never Updated]");
+        let (is_ready, body) = ready(&cfg, true);
+        assert!(!is_ready);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "not ready");
+        assert_eq!(body["locations"][0]["last_update"], serde_json::Value::Null);
+
+        let data = serde_json::from_str(r#"{"name": "Health Test"}"#).unwrap();
+        exporter::set_location_metrics("Health Test", &data);
+        let cfg = config::test_configuration("locations: [Health Test Never Updated, Health Test]");
+        let (is_ready, body) = ready(&cfg, true);
+        assert!(is_ready);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["locations"][0]["current"], false);
+        assert_eq!(body["locations"][1]["current"], true);
+
+        // Without details, the locations aren't disclosed
+        let (is_ready, body) = ready(&cfg, false);
+        assert!(is_ready);
+        assert_eq!(body, r#"{"status":"ready"}"#);
+    }
+}
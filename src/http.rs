@@ -1,14 +1,60 @@
+use crate::auth;
+use crate::budget;
 use crate::config;
 use crate::constants;
 use crate::exporter;
+use crate::health;
+use crate::influxdb;
+use crate::listener;
+use crate::openmetrics;
+use crate::openweathermap;
+use crate::probe;
+use crate::tls;
 
-use log::{debug, info};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use log::{debug, info, warn};
+use rand::Rng;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use std::io::Write;
 use std::net::ToSocketAddrs;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn build_client(timeout_sec: u64) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
-    let timeout = Duration::from_secs(timeout_sec);
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either the native-tls or the rustls feature must be enabled");
+
+fn client_builder(cfg: &config::Configuration) -> reqwest::blocking::ClientBuilder {
+    let timeout = Duration::from_secs(cfg.timeout.unwrap_or(constants::HTTP_CLIENT_TIMEOUT));
+
+    let http_client_builder = reqwest::blocking::ClientBuilder::new()
+        .user_agent(constants::generate_default_user_agent())
+        .timeout(timeout);
+
+    #[cfg(feature = "rustls")]
+    let http_client_builder = http_client_builder.use_rustls_tls();
+
+    http_client_builder
+}
+
+// Client for outputs like the Pushgateway, the proxy and TLS settings only apply to the
+// OpenWeatherMap API
+pub fn build_client(
+    cfg: &config::Configuration,
+) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+    match client_builder(cfg).build() {
+        Ok(v) => Ok(v),
+        Err(e) => bail!("can't create HTTP client: {}", e),
+    }
+}
+
+// Client for the OpenWeatherMap API
+pub fn build_api_client(
+    cfg: &config::Configuration,
+) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         "X-Clacks-Overhead",
@@ -19,10 +65,40 @@ pub fn build_client(timeout_sec: u64) -> Result<reqwest::blocking::Client, Box<d
         reqwest::header::HeaderValue::from_static("application/json"),
     );
 
-    let http_client_builder = reqwest::blocking::ClientBuilder::new()
-        .user_agent(constants::generate_default_user_agent())
-        .default_headers(headers)
-        .timeout(timeout);
+    let mut http_client_builder = client_builder(cfg).default_headers(headers);
+
+    if let Some(proxy_cfg) = &cfg.proxy {
+        let mut proxy = match reqwest::Proxy::all(&proxy_cfg.url) {
+            Ok(v) => v,
+            Err(e) => bail!("can't parse proxy URL {}: {}", proxy_cfg.url, e),
+        };
+        if let (Some(user), Some(pass)) = (&proxy_cfg.username, &proxy_cfg.password) {
+            proxy = proxy.basic_auth(user, pass);
+        }
+        if let Some(no_proxy) = &proxy_cfg.no_proxy {
+            proxy = proxy.no_proxy(reqwest::NoProxy::from_string(no_proxy));
+        }
+        http_client_builder = http_client_builder.proxy(proxy);
+    }
+
+    if let Some(ca_file) = &cfg.ca_file {
+        let pem = match fs::read(ca_file) {
+            Ok(v) => v,
+            Err(e) => bail!("can't read CA file {}: {}", ca_file, e),
+        };
+        let ca_certs = match reqwest::Certificate::from_pem_bundle(&pem) {
+            Ok(v) => v,
+            Err(e) => bail!("can't parse CA file {}: {}", ca_file, e),
+        };
+        for ca_cert in ca_certs {
+            http_client_builder = http_client_builder.add_root_certificate(ca_cert);
+        }
+    }
+
+    if let (Some(cert_file), Some(key_file)) = (&cfg.client_certificate, &cfg.client_key) {
+        let identity = load_client_identity(cert_file, key_file)?;
+        http_client_builder = http_client_builder.identity(identity);
+    }
 
     let http_client = match http_client_builder.build() {
         Ok(v) => v,
@@ -32,22 +108,287 @@ pub fn build_client(timeout_sec: u64) -> Result<reqwest::blocking::Client, Box<d
     Ok(http_client)
 }
 
+fn load_client_identity(
+    cert_file: &str,
+    key_file: &str,
+) -> Result<reqwest::Identity, Box<dyn Error>> {
+    let cert = match fs::read(cert_file) {
+        Ok(v) => v,
+        Err(e) => bail!("can't read client certificate {}: {}", cert_file, e),
+    };
+    let key = match fs::read(key_file) {
+        Ok(v) => v,
+        Err(e) => bail!("can't read client key {}: {}", key_file, e),
+    };
+
+    // rustls expects certificate and private key in a single PEM buffer
+    #[cfg(feature = "rustls")]
+    let identity = reqwest::Identity::from_pem(&[key, cert].concat());
+
+    #[cfg(not(feature = "rustls"))]
+    let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key);
+
+    match identity {
+        Ok(v) => Ok(v),
+        Err(e) => bail!("can't load client certificate {}: {}", cert_file, e),
+    }
+}
+
+// If a deadline is given, requests are aborted and not retried when it is reached.
+// The timeout of the client is required because a timeout of the request replaces it.
 pub fn get(
     http_client: &mut reqwest::blocking::Client,
     url: &str,
+    retry_cfg: &config::RetryConfiguration,
+    timeout: Duration,
+    deadline: Option<Instant>,
 ) -> Result<String, Box<dyn Error>> {
-    debug!("GET {}", &url);
+    let max_retries = retry_cfg.retries.unwrap_or(constants::DEFAULT_RETRIES);
+    let mut attempt: u32 = 0;
+
+    loop {
+        debug!("GET {}", &url);
+
+        let mut request = http_client.get(url);
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Box::new(openweathermap::ApiError {
+                    kind: openweathermap::ApiErrorKind::Timeout,
+                    status: None,
+                    message: "scrape timeout reached".to_string(),
+                }));
+            }
+            request = request.timeout(remaining.min(timeout));
+        }
+
+        let (error, retry_after) = match request.send() {
+            Ok(response) => {
+                let status = response.status();
+                if status == reqwest::StatusCode::OK {
+                    let reply = response.text()?;
+                    return Ok(reply);
+                }
+
+                let retry_after = parse_retry_after(response.headers());
+                let body = response.text().unwrap_or_default();
+                (
+                    openweathermap::ApiError::from_response(status.as_u16(), &body),
+                    retry_after,
+                )
+            }
+            Err(e) => (openweathermap::ApiError::from_transport(&e), None),
+        };
 
-    let response = http_client.get(url).send()?;
-    if response.status() != reqwest::StatusCode::OK {
-        bail!(
-            "HTTP connection returned HTTP status code \"{}\" instead of \"200 OK\"",
-            response.status()
+        if !error.kind.is_retryable() || attempt >= max_retries {
+            return Err(Box::new(error));
+        }
+        let reason = error.kind.as_str();
+
+        let delay = retry_delay(retry_cfg, attempt, retry_after);
+        if deadline.is_some_and(|v| Instant::now() + delay >= v) {
+            debug!("Not retrying request, the scrape timeout would be exceeded");
+            return Err(Box::new(error));
+        }
+
+        if !budget::acquire() {
+            bail!(
+                "request failed ({}) and API call budget is exhausted",
+                reason
+            );
+        }
+
+        attempt += 1;
+        exporter::HTTP_RETRIES.with_label_values(&[reason]).inc();
+        warn!(
+            "Request failed ({}), retrying in {} ms ({} of {})",
+            reason,
+            delay.as_millis(),
+            attempt,
+            max_retries
         );
+        thread::sleep(delay);
+    }
+}
+
+// Delays requested by the server are capped at max_delay, the deadline of a scrape is
+// checked by the caller
+fn retry_delay(
+    retry_cfg: &config::RetryConfiguration,
+    attempt: u32,
+    retry_after: Option<Duration>,
+) -> Duration {
+    let max_delay = Duration::from_secs(
+        retry_cfg
+            .max_delay
+            .unwrap_or(constants::DEFAULT_RETRY_MAX_DELAY),
+    );
+    match retry_after {
+        Some(v) if v > max_delay => {
+            warn!(
+                "Server requested to retry after {} seconds, waiting for the maximal retry delay of {} seconds",
+                v.as_secs(),
+                max_delay.as_secs()
+            );
+            max_delay
+        }
+        Some(v) => v,
+        None => backoff_delay(retry_cfg, attempt),
+    }
+}
+
+// Exponential backoff with "full jitter", see
+// https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+fn backoff_delay(retry_cfg: &config::RetryConfiguration, attempt: u32) -> Duration {
+    let initial_delay = retry_cfg
+        .initial_delay
+        .unwrap_or(constants::DEFAULT_RETRY_INITIAL_DELAY)
+        .saturating_mul(1000);
+    let max_delay = retry_cfg
+        .max_delay
+        .unwrap_or(constants::DEFAULT_RETRY_MAX_DELAY)
+        .saturating_mul(1000);
+    let delay = initial_delay
+        .saturating_mul(2_u64.saturating_pow(attempt))
+        .min(max_delay);
+
+    Duration::from_millis(rand::thread_rng().gen_range(0..=delay))
+}
+
+// Retry-After can be either a number of seconds or a HTTP date (RFC 7231, section 7.1.3)
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    let secs = date.timestamp() - chrono::Utc::now().timestamp();
+    Some(Duration::from_secs(secs.max(0) as u64))
+}
+
+// Preferred content coding of the response, gzip is used if both are accepted equally
+fn negotiate_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let mut qualities = HashMap::new();
+    for coding in accept_encoding.split(',') {
+        let mut parts = coding.split(';').map(|v| v.trim());
+        let name = parts.next().unwrap_or_default().to_lowercase();
+        let quality = parts
+            .filter_map(|v| v.strip_prefix("q="))
+            .next()
+            .map(|v| v.parse().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        qualities.insert(name, quality);
+    }
+
+    let quality = |name: &str| -> f64 {
+        qualities
+            .get(name)
+            .or_else(|| qualities.get("*"))
+            .copied()
+            .unwrap_or(0.0)
+    };
+    let gzip = quality("gzip");
+    let deflate = quality("deflate");
+    if gzip > 0.0 && gzip >= deflate {
+        Some("gzip")
+    } else if deflate > 0.0 {
+        Some("deflate")
+    } else {
+        None
+    }
+}
+
+// The deflate content coding is the zlib format (RFC 9110, section 8.4.1.2)
+fn compress(body: &[u8], encoding: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let compressed = match encoding {
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()?
+        }
+        "deflate" => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()?
+        }
+        _ => bail!("unsupported content coding {}", encoding),
+    };
+    Ok(compressed)
+}
+
+fn negotiate_format(req: &oxhttp::model::Request) -> openmetrics::Format {
+    match req.header(&oxhttp::model::HeaderName::ACCEPT) {
+        Some(v) => openmetrics::negotiate(&String::from_utf8_lossy(v)),
+        None => openmetrics::Format::Text,
     }
+}
+
+// Prometheus sends its scrape timeout, the reply must be sent before it is reached
+fn scrape_deadline(cfg: &config::Configuration, req: &oxhttp::model::Request) -> Option<Instant> {
+    let name: oxhttp::model::HeaderName = constants::SCRAPE_TIMEOUT_HEADER.parse().ok()?;
+    let value = String::from_utf8_lossy(req.header(&name)?).to_string();
+    let timeout = match value.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => v,
+        _ => {
+            debug!("Ignoring invalid scrape timeout {}", value);
+            return None;
+        }
+    };
 
-    let reply = response.text()?;
-    Ok(reply)
+    let offset = cfg
+        .scrape_timeout_offset
+        .unwrap_or(constants::DEFAULT_SCRAPE_TIMEOUT_OFFSET);
+    // The header is sent by the client, values too large for a deadline mean no deadline
+    let deadline = Duration::try_from_secs_f64((timeout - offset).max(0.0))
+        .ok()
+        .and_then(|v| Instant::now().checked_add(v).map(|deadline| (v, deadline)));
+    match deadline {
+        Some((timeout, deadline)) => {
+            debug!("Updates must finish within {} ms", timeout.as_millis());
+            Some(deadline)
+        }
+        None => {
+            debug!("Ignoring scrape timeout {}, it is too large", value);
+            None
+        }
+    }
+}
+
+// Metrics are compressed if the client accepts it
+fn metrics_response(
+    req: &oxhttp::model::Request,
+    format: openmetrics::Format,
+    mut reply: String,
+) -> oxhttp::model::Response {
+    let builder = oxhttp::model::Response::builder(oxhttp::model::Status::OK)
+        .with_header(
+            oxhttp::model::HeaderName::CONTENT_TYPE,
+            format.content_type(),
+        )
+        .unwrap()
+        .with_header(oxhttp::model::HeaderName::VARY, "Accept, Accept-Encoding")
+        .unwrap();
+    if reply.is_empty() {
+        debug!("No metrics to return, sending an empty line");
+        reply = "\n".to_string();
+    }
+
+    let encoding = req
+        .header(&oxhttp::model::HeaderName::ACCEPT_ENCODING)
+        .and_then(|v| negotiate_encoding(&String::from_utf8_lossy(v)));
+    match encoding.map(|v| (v, compress(reply.as_bytes(), v))) {
+        Some((encoding, Ok(compressed))) => builder
+            .with_header(oxhttp::model::HeaderName::CONTENT_ENCODING, encoding)
+            .unwrap()
+            .with_body(compressed),
+        Some((encoding, Err(e))) => {
+            warn!("Can't compress metrics using {}: {}", encoding, e);
+            builder.with_body(reply)
+        }
+        None => builder.with_body(reply),
+    }
 }
 
 fn socketaddr_from_listen(listen: &str) -> Result<std::net::SocketAddr, Box<dyn Error>> {
@@ -59,13 +400,42 @@ fn socketaddr_from_listen(listen: &str) -> Result<std::net::SocketAddr, Box<dyn
     Ok(addresses[0])
 }
 
-pub fn server(cfg: config::Configuration, listen_address: &str) -> Result<(), Box<dyn Error>> {
+pub fn server(
+    shared_cfg: Arc<RwLock<config::Configuration>>,
+    listen_address: &str,
+) -> Result<(), Box<dyn Error>> {
     let socketaddr = socketaddr_from_listen(listen_address)?;
 
-    let mut srv = oxhttp::Server::new(move |req| {
-        let response: oxhttp::model::Response;
+    // Fail early if the certificate or key can't be loaded
+    let tls = match tls::server_config(&shared_cfg.read().unwrap()) {
+        Ok(v) => v.is_some(),
+        Err(e) => bail!("can't load TLS certificate: {}", e),
+    };
+
+    let handler_cfg = shared_cfg.clone();
+    let handler = Arc::new(move |req: &mut oxhttp::model::Request| {
+        // Requests work on a copy of the configuration, so slow updates don't block a reload
+        let cfg = handler_cfg.read().unwrap().clone();
+        let mut response: oxhttp::model::Response;
+
+        // Health checks, e.g. of Kubernetes, usually can't authenticate
+        let public = matches!(
+            req.url().path(),
+            constants::HEALTHY_PATH | constants::READY_PATH
+        );
+        let authorized = auth::is_authorized(&cfg, req);
+        let challenge = if public || authorized {
+            None
+        } else {
+            Some(auth::challenge(&cfg))
+        };
 
-        if req.method() != &oxhttp::model::Method::GET {
+        if let Some(challenge) = challenge {
+            response = oxhttp::model::Response::builder(oxhttp::model::Status::UNAUTHORIZED)
+                .with_header(oxhttp::model::HeaderName::WWW_AUTHENTICATE, challenge)
+                .unwrap()
+                .with_body("Unauthorized");
+        } else if req.method() != &oxhttp::model::Method::GET {
             response = oxhttp::model::Response::builder(oxhttp::model::Status::METHOD_NOT_ALLOWED)
                 .with_body("Method not allowed");
         } else {
@@ -74,16 +444,91 @@ pub fn server(cfg: config::Configuration, listen_address: &str) -> Result<(), Bo
                     response = oxhttp::model::Response::builder(oxhttp::model::Status::OK)
                         .with_body(constants::ROOT_HTML);
                 }
-                constants::METRICS_PATH => {
-                    let reply = exporter::serve_metrics(&cfg);
-                    if reply.is_empty() {
-                        println!("empty reply");
-                        response = oxhttp::model::Response::builder(oxhttp::model::Status::OK)
-                            .with_body("\n");
+                constants::HEALTHY_PATH => {
+                    response = oxhttp::model::Response::builder(oxhttp::model::Status::OK)
+                        .with_header(
+                            oxhttp::model::HeaderName::CONTENT_TYPE,
+                            constants::JSON_CONTENT_TYPE,
+                        )
+                        .unwrap()
+                        .with_body(health::healthy());
+                }
+                constants::READY_PATH => {
+                    // The configured locations are only shown to authorized clients
+                    let (ready, body) = health::ready(&cfg, authorized);
+                    let status = if ready {
+                        oxhttp::model::Status::OK
                     } else {
-                        response = oxhttp::model::Response::builder(oxhttp::model::Status::OK)
-                            .with_body(reply);
-                    }
+                        oxhttp::model::Status::SERVICE_UNAVAILABLE
+                    };
+                    response = oxhttp::model::Response::builder(status)
+                        .with_header(
+                            oxhttp::model::HeaderName::CONTENT_TYPE,
+                            constants::JSON_CONTENT_TYPE,
+                        )
+                        .unwrap()
+                        .with_body(body);
+                }
+                constants::METRICS_PATH => {
+                    let format = negotiate_format(req);
+                    let deadline = scrape_deadline(&cfg, req);
+                    response = metrics_response(
+                        req,
+                        format,
+                        exporter::serve_metrics(&cfg, format, deadline),
+                    );
+                }
+                constants::PROBE_PATH => {
+                    let query: Vec<(String, String)> = req
+                        .url()
+                        .query_pairs()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect();
+                    match (&cfg.probe, probe::parse_target(&query)) {
+                        (None, _) => {
+                            response =
+                                oxhttp::model::Response::builder(oxhttp::model::Status::NOT_FOUND)
+                                    .with_body("Not found");
+                        }
+                        (Some(_), Err(e)) => {
+                            response = oxhttp::model::Response::builder(
+                                oxhttp::model::Status::BAD_REQUEST,
+                            )
+                            .with_body(format!("Invalid probe target: {}", e));
+                        }
+                        (Some(probe_cfg), Ok(location)) => {
+                            if probe::is_allowed(probe_cfg, &location) {
+                                let format = negotiate_format(req);
+                                let deadline = scrape_deadline(&cfg, req);
+                                response = metrics_response(
+                                    req,
+                                    format,
+                                    probe::probe(&cfg, &location, format, deadline),
+                                );
+                            } else {
+                                warn!("Rejecting probe of {}, location is not allowed", location);
+                                response = oxhttp::model::Response::builder(
+                                    oxhttp::model::Status::FORBIDDEN,
+                                )
+                                .with_body("Location not allowed");
+                            }
+                        }
+                    };
+                }
+                constants::INFLUXDB_PATH => {
+                    match &cfg.influxdb {
+                        // Weather data is updated in the background, so requests don't use
+                        // the API call budget
+                        Some(influxdb_cfg) => {
+                            response = oxhttp::model::Response::builder(oxhttp::model::Status::OK)
+                                .with_body(influxdb::encode(influxdb_cfg));
+                        }
+                        None => {
+                            response =
+                                oxhttp::model::Response::builder(oxhttp::model::Status::NOT_FOUND)
+                                    .with_body("Not found");
+                        }
+                    };
                 }
                 _ => {
                     response = oxhttp::model::Response::builder(oxhttp::model::Status::NOT_FOUND)
@@ -91,18 +536,289 @@ pub fn server(cfg: config::Configuration, listen_address: &str) -> Result<(), Bo
                 }
             };
         }
+
+        // Headers of the web configuration file, e.g. Strict-Transport-Security
+        for (name, value) in &cfg.http_headers {
+            if let (Ok(name), Ok(value)) = (
+                name.parse::<oxhttp::model::HeaderName>(),
+                value.parse::<oxhttp::model::HeaderValue>(),
+            ) {
+                response.headers_mut().set(name, value);
+            }
+        }
         response
     });
 
-    srv.set_global_timeout(std::time::Duration::from_secs(
-        constants::HTTP_CLIENT_TIMEOUT,
-    ));
+    if tls {
+        info!("Starting web server with TLS on {}", listen_address);
+    } else {
+        info!("Starting web server on {}", listen_address);
+    }
+    listener::listen(socketaddr, shared_cfg, handler)
+}
 
-    info!("Starting web server on {}", listen_address);
-    match srv.listen(socketaddr) {
-        Ok(_) => {}
-        Err(e) => bail!("{}", e),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(headers: &[(&str, &str)]) -> oxhttp::model::Request {
+        let mut builder = oxhttp::model::Request::builder(
+            oxhttp::model::Method::GET,
+            "http://localhost/metrics".parse().unwrap(),
+        );
+        for (name, value) in headers {
+            builder = builder
+                .with_header(name.parse::<oxhttp::model::HeaderName>().unwrap(), *value)
+                .unwrap();
+        }
+        builder.build()
+    }
+
+    fn retry_after(value: &str) -> Option<Duration> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, value.parse().unwrap());
+        parse_retry_after(&headers)
+    }
+
+    #[test]
+    fn test_parse_retry_after_seconds() {
+        assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 5 "), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_parse_retry_after_date() {
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+
+        // Dates in the past mean no delay
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after_invalid() {
+        assert_eq!(parse_retry_after(&reqwest::header::HeaderMap::new()), None);
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(retry_after("-1"), None);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let retry_cfg = config::RetryConfiguration {
+            retries: Some(5),
+            initial_delay: Some(2),
+            max_delay: Some(10),
+        };
+        for _ in 0..100 {
+            assert!(backoff_delay(&retry_cfg, 0) <= Duration::from_secs(2));
+            assert!(backoff_delay(&retry_cfg, 1) <= Duration::from_secs(4));
+            assert!(backoff_delay(&retry_cfg, 10) <= Duration::from_secs(10));
+            assert!(backoff_delay(&retry_cfg, u32::MAX) <= Duration::from_secs(10));
+        }
+    }
 
-    Ok(())
+    #[test]
+    fn test_retry_delay() {
+        let retry_cfg = config::RetryConfiguration {
+            retries: Some(5),
+            initial_delay: Some(2),
+            max_delay: Some(10),
+        };
+        let delay = |v| retry_delay(&retry_cfg, 0, Some(Duration::from_secs(v)));
+        assert_eq!(delay(5), Duration::from_secs(5));
+        // Longer delays requested by the server are capped
+        assert_eq!(delay(3600), Duration::from_secs(10));
+        assert!(retry_delay(&retry_cfg, 0, None) <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_backoff_delay_defaults() {
+        let retry_cfg = config::RetryConfiguration::default();
+        let max_delay = Duration::from_secs(constants::DEFAULT_RETRY_MAX_DELAY);
+        for attempt in 0..20 {
+            assert!(backoff_delay(&retry_cfg, attempt) <= max_delay);
+        }
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(negotiate_encoding("gzip"), Some("gzip"));
+        assert_eq!(negotiate_encoding("deflate"), Some("deflate"));
+        assert_eq!(negotiate_encoding("gzip, deflate, br"), Some("gzip"));
+        assert_eq!(negotiate_encoding("gzip;q=0.5, deflate"), Some("deflate"));
+        assert_eq!(negotiate_encoding("GZIP"), Some("gzip"));
+        assert_eq!(negotiate_encoding("*"), Some("gzip"));
+        assert_eq!(negotiate_encoding("*, gzip;q=0"), Some("deflate"));
+    }
+
+    #[test]
+    fn test_negotiate_encoding_none() {
+        assert_eq!(negotiate_encoding(""), None);
+        assert_eq!(negotiate_encoding("identity"), None);
+        assert_eq!(negotiate_encoding("br"), None);
+        assert_eq!(negotiate_encoding("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(negotiate_encoding("gzip;q=invalid"), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let body = b"openweathermap_temperature_celsius 21.5\n".repeat(100);
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(compress(&body, "gzip").unwrap().as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(compress(&body, "deflate").unwrap().as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        assert_eq!(negotiate_format(&request(&[])), openmetrics::Format::Text);
+        assert_eq!(
+            negotiate_format(&request(&[("accept", "text/plain;version=0.0.4")])),
+            openmetrics::Format::Text
+        );
+        assert_eq!(
+            negotiate_format(&request(&[(
+                "accept",
+                "application/openmetrics-text;version=1.0.0"
+            )])),
+            openmetrics::Format::OpenMetrics
+        );
+    }
+
+    #[test]
+    fn test_scrape_deadline() {
+        let cfg = config::test_configuration("");
+        let now = Instant::now();
+        let deadline =
+            scrape_deadline(&cfg, &request(&[(constants::SCRAPE_TIMEOUT_HEADER, "10")])).unwrap();
+        assert!(deadline >= now + Duration::from_millis(9500));
+        assert!(deadline <= Instant::now() + Duration::from_millis(9500));
+
+        let cfg = config::test_configuration("scrape_timeout_offset: 2.5");
+        let now = Instant::now();
+        let deadline =
+            scrape_deadline(&cfg, &request(&[(constants::SCRAPE_TIMEOUT_HEADER, "4.5")])).unwrap();
+        assert!(deadline >= now + Duration::from_secs(2));
+        assert!(deadline <= Instant::now() + Duration::from_secs(2));
+
+        // The deadline is never in the past
+        let now = Instant::now();
+        let deadline =
+            scrape_deadline(&cfg, &request(&[(constants::SCRAPE_TIMEOUT_HEADER, "1")])).unwrap();
+        assert!(deadline >= now && deadline <= Instant::now());
+    }
+
+    #[test]
+    fn test_scrape_deadline_invalid() {
+        let cfg = config::test_configuration("");
+        assert_eq!(scrape_deadline(&cfg, &request(&[])), None);
+        for value in ["", "soon", "0", "-10", "NaN", "inf"] {
+            assert_eq!(
+                scrape_deadline(&cfg, &request(&[(constants::SCRAPE_TIMEOUT_HEADER, value)])),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_scrape_deadline_too_large() {
+        let cfg = config::test_configuration("");
+        for value in ["1e19", "1e30", "1.7e308"] {
+            assert_eq!(
+                scrape_deadline(&cfg, &request(&[(constants::SCRAPE_TIMEOUT_HEADER, value)])),
+                None
+            );
+        }
+    }
+
+    const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/ca.pem");
+    const CLIENT_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/client.pem");
+    const CLIENT_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/client.key");
+
+    // Proxy that returns the head of the first request it receives and rejects it
+    fn capturing_proxy() -> (String, std::sync::mpsc::Receiver<String>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = [0; 4096];
+            let length = stream.read(&mut head).unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n");
+            sender
+                .send(String::from_utf8_lossy(&head[..length]).to_string())
+                .unwrap();
+        });
+        (format!("http://127.0.0.1:{}", port), receiver)
+    }
+
+    #[test]
+    fn test_build_api_client_proxy() {
+        let (url, head) = capturing_proxy();
+        let cfg = config::test_configuration(&format!(
+            "proxy: {{url: '{}', username: user, password: secret}}",
+            url
+        ));
+        let client = build_api_client(&cfg).unwrap();
+        assert!(client.get(constants::OWM_URL).send().is_err());
+
+        let head = head.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(head.starts_with("CONNECT api.openweathermap.org:443 "));
+        // user:secret
+        assert!(head
+            .to_lowercase()
+            .contains("proxy-authorization: basic dxnlcjpzzwnyzxq="));
+    }
+
+    #[test]
+    fn test_build_api_client_invalid_proxy() {
+        let cfg = config::test_configuration("proxy: {url: 'http://[::1'}");
+        let error = build_api_client(&cfg).unwrap_err().to_string();
+        assert!(error.starts_with("can't parse proxy URL"), "{}", error);
+    }
+
+    #[test]
+    fn test_build_api_client_tls() {
+        let cfg = config::test_configuration(&format!(
+            "ca_file: {}\nclient_certificate: {}\nclient_key: {}",
+            CA, CLIENT_CERT, CLIENT_KEY
+        ));
+        assert!(build_api_client(&cfg).is_ok());
+
+        let cfg = config::test_configuration("ca_file: /nonexistent.pem");
+        let error = build_api_client(&cfg).unwrap_err().to_string();
+        assert!(error.starts_with("can't read CA file"), "{}", error);
+
+        let cfg = config::test_configuration(&format!(
+            "client_certificate: {}\nclient_key: /nonexistent.key",
+            CLIENT_CERT
+        ));
+        let error = build_api_client(&cfg).unwrap_err().to_string();
+        assert!(error.starts_with("can't read client key"), "{}", error);
+
+        // Certificate without key
+        let cfg = config::test_configuration(&format!(
+            "client_certificate: {}\nclient_key: {}",
+            CLIENT_CERT, CLIENT_CERT
+        ));
+        let error = build_api_client(&cfg).unwrap_err().to_string();
+        assert!(
+            error.starts_with("can't load client certificate"),
+            "{}",
+            error
+        );
+    }
 }
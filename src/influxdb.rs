@@ -0,0 +1,136 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::http;
+
+use log::debug;
+use std::error::Error;
+
+// Escaping rules of the line protocol, see
+// https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/#special-characters
+// Backslashes must be escaped first, or the backslashes added for other characters would be doubled
+fn escape_measurement(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(' ', "\\ ")
+}
+
+fn escape_tag(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+pub fn encode(influxdb_cfg: &config::InfluxDBConfiguration) -> String {
+    let measurement = escape_measurement(
+        influxdb_cfg
+            .measurement
+            .as_deref()
+            .unwrap_or(constants::DEFAULT_INFLUXDB_MEASUREMENT),
+    );
+    let mut result = String::new();
+
+    for (_, data) in exporter::observations() {
+        let values = data.values();
+        if values.is_empty() {
+            continue;
+        }
+
+        // Empty tag values are not allowed
+        let mut line = measurement.clone();
+        for (tag, value) in [
+            ("name", data.name.clone()),
+            ("country", data.country().to_string()),
+            ("id", data.id.map(|v| v.to_string()).unwrap_or_default()),
+        ] {
+            if !value.is_empty() {
+                line.push_str(&format!(",{}={}", tag, escape_tag(&value)));
+            }
+        }
+
+        let fields: Vec<String> = values
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        line.push(' ');
+        line.push_str(&fields.join(","));
+
+        if let Some(dt) = data.dt {
+            line.push_str(&format!(" {}", dt * 1_000_000_000));
+        }
+
+        result.push_str(&line);
+        result.push('\n');
+    }
+    result
+}
+
+// Send data to the write API of InfluxDB 2.x
+pub fn write(
+    cfg: &config::Configuration,
+    influxdb_cfg: &config::InfluxDBConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let url = match &influxdb_cfg.url {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    let body = encode(influxdb_cfg);
+    if body.is_empty() {
+        debug!("No weather data to write to InfluxDB");
+        return Ok(());
+    }
+
+    let client = http::build_client(cfg)?;
+    let mut request = client
+        .post(format!("{}/api/v2/write", url.trim_end_matches('/')))
+        .query(&[
+            ("org", influxdb_cfg.org.as_deref().unwrap_or_default()),
+            ("bucket", influxdb_cfg.bucket.as_deref().unwrap_or_default()),
+            ("precision", "ns"),
+        ])
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body);
+    if let Some(token) = &influxdb_cfg.token {
+        request = request.header(reqwest::header::AUTHORIZATION, format!("Token {}", token));
+    }
+
+    debug!("Writing weather data to {}", url);
+    let response = request.send()?;
+    if !response.status().is_success() {
+        bail!(
+            "InfluxDB returned HTTP status code \"{}\": {}",
+            response.status(),
+            response.text().unwrap_or_default().trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_measurement(r"a,b c\d=e"), r"a\,b\ c\\d=e");
+        assert_eq!(escape_tag(r"a,b c\d=e"), r"a\,b\ c\\d\=e");
+    }
+
+    #[test]
+    fn test_encode() {
+        let data = serde_json::from_str(
+            r#"{"dt": 1700000000, "id": 1, "name": "Influx Test, =1", "sys": {"country": "DE"},
+                "main": {"temp": 21.5, "humidity": 50}}"#,
+        )
+        .unwrap();
+        exporter::set_location_metrics("influxdb test", &data);
+
+        let influxdb_cfg: config::InfluxDBConfiguration =
+            serde_yaml::from_str("measurement: my weather").unwrap();
+        let result = encode(&influxdb_cfg);
+        assert!(result.lines().any(|v| v
+            == r"my\ weather,name=Influx\ Test\,\ \=1,country=DE,id=1 temperature=21.5,humidity=0.5 1700000000000000000"));
+    }
+}
@@ -0,0 +1,535 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::tls;
+
+use log::{debug, warn};
+use oxhttp::model::{HeaderName, Method, Request, Response, Status, Url};
+use std::cell::Cell;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type Handler = dyn Fn(&mut Request) -> Response + Send + Sync;
+
+// Number of connections being served, limited to MAX_HTTP_CONNECTIONS
+#[derive(Default)]
+struct Connections {
+    count: Mutex<usize>,
+    closed: Condvar,
+}
+
+// Releases the slot of a connection when its thread ends
+struct ConnectionGuard(Arc<Connections>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap() -= 1;
+        self.0.closed.notify_one();
+    }
+}
+
+impl Connections {
+    // Wait until a connection is closed if the limit is reached, further connections are kept
+    // in the backlog of the listening socket
+    fn acquire(self: &Arc<Self>) -> ConnectionGuard {
+        let mut count = self.count.lock().unwrap();
+        if *count >= constants::MAX_HTTP_CONNECTIONS {
+            debug!("Maximal number of connections reached, waiting for a connection to close");
+        }
+        while *count >= constants::MAX_HTTP_CONNECTIONS {
+            count = self.closed.wait(count).unwrap();
+        }
+        *count += 1;
+        ConnectionGuard(self.clone())
+    }
+}
+
+// oxhttp::Server doesn't support TLS, so connections are accepted here and requests are
+// passed to the handler using the request and response model of oxhttp
+pub fn listen(
+    socketaddr: SocketAddr,
+    shared_cfg: Arc<RwLock<config::Configuration>>,
+    handler: Arc<Handler>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(socketaddr)?;
+    let connections = Arc::new(Connections::default());
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Can't accept connection: {}", e);
+                continue;
+            }
+        };
+        // Connections from addresses that aren't allowed are closed at once, they must not
+        // take a connection slot or get to the TLS handshake
+        let peer = match stream.peer_addr() {
+            Ok(v) => canonical(v.ip()),
+            Err(e) => {
+                debug!("Can't get address of connection: {}", e);
+                continue;
+            }
+        };
+        if !is_allowed(&shared_cfg.read().unwrap(), peer) {
+            debug!("Rejecting connection from {}, address is not allowed", peer);
+            exporter::HTTP_REJECTED.inc();
+            continue;
+        }
+
+        let guard = connections.acquire();
+        let shared_cfg = shared_cfg.clone();
+        let handler = handler.clone();
+        thread::spawn(move || {
+            let _guard = guard;
+            let peer = stream
+                .peer_addr()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            if let Err(e) = accept(stream, &shared_cfg, handler.as_ref()) {
+                debug!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// The read timeout of the socket only limits the time between two reads, so a client sending
+// one byte at a time could keep a connection busy forever. While a deadline is set, the read
+// timeout is shortened to the time left until the deadline.
+struct DeadlineStream<'a> {
+    stream: TcpStream,
+    deadline: &'a Cell<Option<Instant>>,
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = Duration::from_secs(constants::HTTP_CLIENT_TIMEOUT);
+        let timeout = match self.deadline.get() {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request not received in time",
+                    ));
+                }
+                remaining.min(timeout)
+            }
+            None => timeout,
+        };
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn accept(
+    stream: TcpStream,
+    shared_cfg: &RwLock<config::Configuration>,
+    handler: &Handler,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_write_timeout(Some(Duration::from_secs(constants::HTTP_CLIENT_TIMEOUT)))?;
+
+    // Certificates are reloaded for new connections if they have changed
+    let server_config = tls::server_config(&shared_cfg.read().unwrap())?;
+
+    let deadline = Cell::new(None);
+    let stream = DeadlineStream {
+        stream,
+        deadline: &deadline,
+    };
+    match server_config {
+        Some(v) => {
+            let connection = rustls::ServerConnection::new(v)?;
+            serve(
+                rustls::StreamOwned::new(connection, stream),
+                "https",
+                handler,
+                &deadline,
+            )?;
+        }
+        None => serve(stream, "http", handler, &deadline)?,
+    };
+    Ok(())
+}
+
+// IPv4 clients of a dual-stack socket have IPv4-mapped IPv6 addresses
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v) => v.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        IpAddr::V4(_) => address,
+    }
+}
+
+fn is_allowed(cfg: &config::Configuration, address: IpAddr) -> bool {
+    match &cfg.allowed_ip_networks {
+        Some(networks) => networks.iter().any(|v| v.contains(&address)),
+        None => true,
+    }
+}
+
+fn serve<S: Read + Write>(
+    stream: S,
+    scheme: &str,
+    handler: &Handler,
+    deadline: &Cell<Option<Instant>>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        // The complete request, including the TLS handshake, must be received in time
+        deadline.set(Some(
+            Instant::now() + Duration::from_secs(constants::HTTP_REQUEST_TIMEOUT),
+        ));
+        let head = match read_head(&mut reader)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let request = read_request(&mut reader, &head, scheme);
+        deadline.set(None);
+
+        let (mut response, close) = match request {
+            Ok((mut request, close)) => (handler(&mut request), close),
+            Err((status, message)) => (
+                Response::builder(status).with_body(message.to_string()),
+                true,
+            ),
+        };
+        write_response(reader.get_mut(), &mut response, close)?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+// Read request line and headers, returns None if the client closed the connection
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    // Lines are read until the next line feed, so limit what can be read at all
+    let mut reader = reader.take(constants::MAX_REQUEST_HEADER_SIZE as u64);
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        if reader.read_until(b'\n', &mut head)? == 0 {
+            if reader.limit() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request header too large",
+                ));
+            }
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            // Empty lines before the request line are ignored (RFC 9112, section 2.2)
+            if start == 0 {
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+    }
+}
+
+// Returns the request and whether the connection must be closed after the response
+fn read_request<R: Read>(
+    reader: &mut R,
+    head: &[u8],
+    scheme: &str,
+) -> Result<(Request, bool), (Status, &'static str)> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut headers);
+    match parsed.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return Err((Status::BAD_REQUEST, "Bad request")),
+    };
+
+    let method: Method = match parsed.method.unwrap_or_default().parse() {
+        Ok(v) => v,
+        Err(_) => return Err((Status::BAD_REQUEST, "Invalid method")),
+    };
+    let path = parsed.path.unwrap_or_default();
+    // HTTP/1.1 requests must have exactly one Host header (RFC 9112, section 3.2)
+    let mut hosts = parsed
+        .headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("host"));
+    let host = match (hosts.next(), hosts.next()) {
+        (Some(v), None) => match std::str::from_utf8(v.value) {
+            Ok(v) => v,
+            Err(_) => return Err((Status::BAD_REQUEST, "Invalid host")),
+        },
+        (None, _) if parsed.version != Some(1) => "localhost",
+        _ => return Err((Status::BAD_REQUEST, "Missing or repeated host")),
+    };
+    let url = if path.starts_with('/') {
+        Url::parse(&format!("{}://{}{}", scheme, host, path))
+    } else {
+        Url::parse(path)
+    };
+    let url = match url {
+        Ok(v) => v,
+        Err(_) => return Err((Status::BAD_REQUEST, "Invalid request target")),
+    };
+
+    let mut builder = Request::builder(method, url);
+    let mut content_length = None;
+    // HTTP/1.0 connections are not kept alive
+    let mut close = parsed.version != Some(1);
+    for header in parsed.headers.iter() {
+        if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err((Status::NOT_IMPLEMENTED, "Transfer encoding not supported"));
+        }
+        // Repeated lengths are rejected, they could be read differently by a proxy in front
+        // (RFC 9112, section 6.3)
+        if header.name.eq_ignore_ascii_case("content-length") {
+            let value = std::str::from_utf8(header.value)
+                .ok()
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|v| v.parse::<usize>().ok());
+            content_length = match (value, content_length) {
+                (Some(v), None) => Some(v),
+                _ => return Err((Status::BAD_REQUEST, "Invalid content length")),
+            };
+        }
+        if header.name.eq_ignore_ascii_case("connection")
+            && header.value.eq_ignore_ascii_case(b"close")
+        {
+            close = true;
+        }
+        builder = match builder.with_header(header.name, header.value) {
+            Ok(v) => v,
+            Err(_) => return Err((Status::BAD_REQUEST, "Invalid header")),
+        };
+    }
+
+    let content_length = content_length.unwrap_or(0);
+    if content_length > constants::MAX_REQUEST_HEADER_SIZE {
+        return Err((Status::CONTENT_TOO_LARGE, "Request body too large"));
+    }
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+        return Err((Status::BAD_REQUEST, "Incomplete request body"));
+    }
+
+    Ok((builder.with_body(body), close))
+}
+
+fn write_response<W: Write>(
+    writer: &mut W,
+    response: &mut Response,
+    close: bool,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    response.body_mut().read_to_end(&mut body)?;
+
+    let mut buffer = format!("HTTP/1.1 {}\r\n", response.status()).into_bytes();
+    for (name, value) in response.headers() {
+        if *name == HeaderName::CONTENT_LENGTH
+            || *name == HeaderName::CONNECTION
+            || *name == HeaderName::TRANSFER_ENCODING
+        {
+            continue;
+        }
+        buffer.extend_from_slice(format!("{}: ", name).as_bytes());
+        buffer.extend_from_slice(value);
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer.extend_from_slice(format!("content-length: {}\r\n", body.len()).as_bytes());
+    if close {
+        buffer.extend_from_slice(b"connection: close\r\n");
+    }
+    buffer.extend_from_slice(b"\r\n");
+    buffer.extend_from_slice(&body);
+
+    writer.write_all(&buffer)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<(Request, bool), (Status, &'static str)> {
+        let mut reader = BufReader::new(raw);
+        let head = read_head(&mut reader).unwrap().unwrap();
+        read_request(&mut reader, &head, "http")
+    }
+
+    #[test]
+    fn test_read_head() {
+        let mut reader = BufReader::new(&b"\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\nbody"[..]);
+        assert_eq!(
+            read_head(&mut reader).unwrap().unwrap(),
+            b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"
+        );
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "body");
+    }
+
+    #[test]
+    fn test_read_head_closed() {
+        assert!(read_head(&mut BufReader::new(&b""[..])).unwrap().is_none());
+        assert_eq!(
+            read_head(&mut BufReader::new(&b"GET / HTTP/1.1\r\n"[..]))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_read_head_too_large() {
+        let mut raw = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        raw.resize(constants::MAX_REQUEST_HEADER_SIZE * 2, b'a');
+        assert_eq!(
+            read_head(&mut BufReader::new(&raw[..])).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_read_request() {
+        let (mut request, close) = parse(
+            b"POST /probe?id=1 HTTP/1.1\r\nHost: example.com:9000\r\nContent-Length: 4\r\n\r\ntest",
+        )
+        .unwrap();
+        assert!(!close);
+        assert_eq!(request.method(), &Method::POST);
+        assert_eq!(request.url().as_str(), "http://example.com:9000/probe?id=1");
+        let mut body = String::new();
+        request.body_mut().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "test");
+    }
+
+    #[test]
+    fn test_read_request_close() {
+        assert!(parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().1);
+        assert!(
+            parse(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+                .unwrap()
+                .1
+        );
+    }
+
+    #[test]
+    fn test_read_request_invalid() {
+        assert_eq!(parse(b"GET\r\n\r\n").unwrap_err().0, Status::BAD_REQUEST);
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: x\r\n\r\n")
+                .unwrap_err()
+                .0,
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nshort")
+                .unwrap_err()
+                .0,
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap_err()
+                .0,
+            Status::NOT_IMPLEMENTED
+        );
+        let raw = format!(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n",
+            constants::MAX_REQUEST_HEADER_SIZE + 1
+        );
+        assert_eq!(
+            parse(raw.as_bytes()).unwrap_err().0,
+            Status::CONTENT_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_read_request_content_length() {
+        for raw in [
+            &b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\ntest"[..],
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nContent-Length: 2\r\n\r\ntest",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4, 4\r\n\r\ntest",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +4\r\n\r\ntest",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: \r\n\r\ntest",
+        ] {
+            assert_eq!(parse(raw).unwrap_err().0, Status::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_read_request_host() {
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\n\r\n").unwrap_err().0,
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n")
+                .unwrap_err()
+                .0,
+            Status::BAD_REQUEST
+        );
+        // HTTP/1.0 doesn't require a host
+        let (request, _) = parse(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.url().as_str(), "http://localhost/metrics");
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut response = Response::builder(Status::OK)
+            .with_header(HeaderName::CONTENT_TYPE, "text/plain")
+            .unwrap()
+            .with_body("ok");
+        let mut buffer = Vec::new();
+        write_response(&mut buffer, &mut response, true).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        );
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let cfg = config::test_configuration("");
+        assert!(is_allowed(&cfg, "203.0.113.1".parse().unwrap()));
+
+        let cfg = config::test_configuration("allowed_networks: [127.0.0.1, 10.0.0.0/8]");
+        assert!(is_allowed(&cfg, "127.0.0.1".parse().unwrap()));
+        assert!(is_allowed(&cfg, "10.1.2.3".parse().unwrap()));
+        assert!(!is_allowed(&cfg, "203.0.113.1".parse().unwrap()));
+        assert!(!is_allowed(&cfg, "::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(
+            canonical("::ffff:10.1.2.3".parse().unwrap()),
+            "10.1.2.3".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            canonical("::1".parse().unwrap()),
+            "::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            canonical("10.1.2.3".parse().unwrap()),
+            "10.1.2.3".parse::<IpAddr>().unwrap()
+        );
+    }
+}
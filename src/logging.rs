@@ -1,5 +1,6 @@
-pub fn init(level: log::LevelFilter) -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
+// Log to stderr if stdout is used for other output (e.g. metrics in one-shot mode)
+pub fn init(level: log::LevelFilter, use_stderr: bool) -> Result<(), fern::InitError> {
+    let dispatch = fern::Dispatch::new()
         .format(|logout, logmsg, logrecord| {
             logout.finish(format_args!(
                 "{:<6}: {} {}",
@@ -8,8 +9,12 @@ pub fn init(level: log::LevelFilter) -> Result<(), fern::InitError> {
                 logmsg
             ))
         })
-        .level(level)
-        .chain(std::io::stdout())
-        .apply()?;
+        .level(level);
+
+    if use_stderr {
+        dispatch.chain(std::io::stderr()).apply()?;
+    } else {
+        dispatch.chain(std::io::stdout()).apply()?;
+    }
     Ok(())
 }
@@ -1,16 +1,35 @@
 #[macro_use]
 extern crate simple_error;
 
+mod auth;
+mod budget;
 mod config;
 mod constants;
 mod exporter;
+mod graphite;
+mod health;
 mod http;
+mod influxdb;
+mod listener;
 mod logging;
+mod mqtt;
+mod oneshot;
+mod openmetrics;
 mod openweathermap;
+mod otlp;
+mod outputs;
+mod probe;
+mod pushgateway;
+mod reload;
+mod remote_write;
+mod schedule;
+mod startup;
+mod tls;
 mod usage;
 
 use getopts::Options;
 use log::error;
+use std::sync::{Arc, RwLock};
 use std::{env, process};
 
 fn main() {
@@ -21,9 +40,17 @@ fn main() {
     options.optflag("D", "debug", "Enable debug log");
     options.optflag("V", "version", "Show version");
     options.optopt("c", "config", "Configuration file", "<config_file>");
+    options.optflag("", "check-config", "Check configuration file and exit");
     options.optflag("h", "help", "Show help text");
     options.optopt("l", "listen", "Listen address", "<address>");
     options.optflag("q", "quiet", "Quiet operation");
+    options.optflag("", "once", "Update all locations once and print metrics");
+    options.optopt(
+        "",
+        "textfile",
+        "Update all locations once and write metrics to file",
+        "<file>",
+    );
 
     let opts = match options.parse(&argv[1..]) {
         Ok(v) => v,
@@ -63,29 +90,71 @@ fn main() {
         }
     };
 
-    let listen_address = opts
-        .opt_str("l")
-        .unwrap_or_else(|| constants::DEFAULT_PROMETHEUS_ADDRESS.to_string());
+    // Settings of the web configuration file that are ignored are logged while parsing
+    let textfile = opts.opt_str("textfile");
+    let once = opts.opt_present("once") || textfile.is_some();
 
-    let config = match config::parse_config_file(&config_file) {
-        Ok(v) => v,
+    match logging::init(log_level, once && textfile.is_none()) {
+        Ok(_) => {}
         Err(e) => {
-            eprintln!("Error: Configuration parsing failed: {}", e);
+            eprintln!("Error: Can't initialise logging: {}", e);
             process::exit(1);
         }
     };
 
-    match logging::init(log_level) {
-        Ok(_) => {}
+    let config = match config::parse_config_file(&config_file) {
+        Ok(v) => v,
         Err(e) => {
-            eprintln!("Error: Can't initialise logging: {}", e);
+            eprintln!("Error: Configuration parsing failed: {}", e);
             process::exit(1);
         }
     };
 
+    let listen_address = opts
+        .opt_str("l")
+        .or_else(|| config.listen.clone())
+        .unwrap_or_else(|| constants::DEFAULT_PROMETHEUS_ADDRESS.to_string());
+
+    if let Err(e) = config::validate_address(&listen_address) {
+        eprintln!("Error: Invalid listen address: {}", e);
+        process::exit(1);
+    }
+
+    if opts.opt_present("check-config") {
+        println!("Configuration file {} is valid", config_file);
+        process::exit(0);
+    }
+
+    if let Err(e) = budget::init(&config) {
+        error!("Can't initialise API call budget: {}", e);
+        process::exit(1);
+    }
+
     exporter::register();
+    reload::set_reload_metrics(true);
+
+    if once {
+        if let Err(e) = oneshot::run(&config, textfile.as_deref()) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    schedule::init(&config);
+
+    if config.startup_check.unwrap_or(true) {
+        if let Err(e) = startup::check_locations(&config) {
+            error!("Startup check failed: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let shared_config = Arc::new(RwLock::new(config));
+    reload::watch(&config_file, shared_config.clone());
+    outputs::run_background(shared_config.clone());
 
-    if let Err(e) = http::server(config, &listen_address) {
+    if let Err(e) = http::server(shared_config, &listen_address) {
         error!("Cen't start HTTP server: {}", e);
         process::exit(1);
     };
@@ -0,0 +1,452 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use rumqttc::{Client, ConnectionError, Event, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::json;
+use std::error::Error;
+use std::process;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // Keep the connection to the broker open between updates, it is made again if the
+    // configuration was changed by a reload
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+// Topics and object IDs of Home Assistant only allow a limited set of characters
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Name, unit and device class of a value for Home Assistant discovery
+fn describe(value: &str) -> (&'static str, &'static str, Option<&'static str>) {
+    match value {
+        "temperature" => (constants::METRIC_TEMP_HELP, "°C", Some("temperature")),
+        "apparent_temperature" => (
+            constants::METRIC_TEMP_FEELS_LIKE_HELP,
+            "°C",
+            Some("temperature"),
+        ),
+        "minimal_temperature" => (constants::METRIC_TEMP_MIN_HELP, "°C", Some("temperature")),
+        "maximal_temperature" => (constants::METRIC_TEMP_MAX_HELP, "°C", Some("temperature")),
+        "pressure" => (constants::METRIC_PRESSURE_HELP, "Pa", Some("pressure")),
+        "humidity" => (constants::METRIC_HUMIDITY_HELP, "%", Some("humidity")),
+        "wind_speed" => (constants::METRIC_WIND_SPEED_HELP, "m/s", Some("wind_speed")),
+        "wind_gust" => (constants::METRIC_WIND_GUST_HELP, "m/s", Some("wind_speed")),
+        "wind_direction" => (constants::METRIC_WIND_DIRECTION_HELP, "°", None),
+        "cloud_coverage" => (constants::METRIC_CLOUD_HELP, "%", None),
+        "rain_last_hour" => (constants::METRIC_RAIN_1H_HELP, "mm", Some("precipitation")),
+        "rain_last_three_hours" => (constants::METRIC_RAIN_3H_HELP, "mm", Some("precipitation")),
+        "snow_last_hour" => (constants::METRIC_SNOW_1H_HELP, "mm", Some("precipitation")),
+        "snow_last_three_hours" => (constants::METRIC_SNOW_3H_HELP, "mm", Some("precipitation")),
+        _ => ("", "", None),
+    }
+}
+
+// Topics and payloads of all messages, all messages are retained
+fn messages(mqtt_cfg: &config::MqttConfiguration) -> Vec<(String, String)> {
+    let prefix = mqtt_cfg
+        .topic_prefix
+        .as_deref()
+        .unwrap_or(constants::DEFAULT_MQTT_TOPIC_PREFIX);
+    let discovery_prefix = mqtt_cfg
+        .discovery_prefix
+        .as_deref()
+        .unwrap_or(constants::DEFAULT_MQTT_DISCOVERY_PREFIX);
+    let mut result = Vec::new();
+
+    for (location, data) in exporter::observations() {
+        let values = data.values();
+        if values.is_empty() {
+            continue;
+        }
+
+        let slug = sanitize(&location);
+        let topic = format!("{}/{}", prefix, slug);
+
+        let mut document = json!({
+            "location": location,
+            "name": data.name,
+            "country": data.country(),
+            "id": data.id,
+            "timestamp": data.dt,
+        });
+        for (name, value) in &values {
+            document[*name] = json!(value);
+            result.push((format!("{}/{}", topic, name), value.to_string()));
+        }
+        result.push((topic.clone(), document.to_string()));
+
+        if !mqtt_cfg.homeassistant_discovery.unwrap_or(false) {
+            continue;
+        }
+        let device_id = format!("{}_{}", constants::DEFAULT_MQTT_TOPIC_PREFIX, slug);
+        for (name, _) in &values {
+            let object_id = format!("{}_{}", device_id, name);
+            let (description, unit, device_class) = describe(name);
+            let mut discovery = json!({
+                "name": description,
+                "unique_id": object_id,
+                "object_id": object_id,
+                "state_topic": format!("{}/{}", topic, name),
+                "unit_of_measurement": unit,
+                "state_class": "measurement",
+                "device": {
+                    "identifiers": [device_id],
+                    "name": data.name,
+                    "manufacturer": "OpenWeatherMap",
+                    "sw_version": constants::VERSION,
+                },
+            });
+            if let Some(device_class) = device_class {
+                discovery["device_class"] = json!(device_class);
+            }
+            // Ratios are published between 0 and 1
+            if unit == "%" {
+                discovery["value_template"] = json!("{{ (value | float * 100) | round(0) }}");
+            }
+            result.push((
+                format!("{}/sensor/{}/config", discovery_prefix, object_id),
+                discovery.to_string(),
+            ));
+        }
+    }
+    result
+}
+
+// Connection to the broker with the configuration it was made with
+struct Session {
+    mqtt_cfg: config::MqttConfiguration,
+    client: Client,
+    // Events of the connection, polled by a thread of its own to keep the connection alive
+    events: mpsc::Receiver<Result<Event, ConnectionError>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.client.try_disconnect();
+    }
+}
+
+impl Session {
+    // The thread ends on errors, so a closed channel means the connection is lost
+    fn is_alive(&self) -> bool {
+        loop {
+            match self.events.try_recv() {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    warn!("Connection to MQTT broker lost: {}", e);
+                    return false;
+                }
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+}
+
+fn qos(mqtt_cfg: &config::MqttConfiguration) -> QoS {
+    match mqtt_cfg.qos.unwrap_or(0) {
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtMostOnce,
+    }
+}
+
+fn connect(
+    mqtt_cfg: &config::MqttConfiguration,
+    timeout: Duration,
+) -> Result<Session, Box<dyn Error>> {
+    let (host, port) = match mqtt_cfg.address.rsplit_once(':') {
+        Some(v) => v,
+        None => bail!("missing port in {}", mqtt_cfg.address),
+    };
+    let client_id = match &mqtt_cfg.client_id {
+        Some(v) => v.clone(),
+        None => format!("{}-{}", constants::NAME, process::id()),
+    };
+    let mut options = MqttOptions::new(
+        client_id,
+        host.trim_start_matches('[').trim_end_matches(']'),
+        port.parse()?,
+    );
+    if let (Some(user), Some(pass)) = (&mqtt_cfg.username, &mqtt_cfg.password) {
+        options.set_credentials(user, pass);
+    }
+
+    let (client, mut connection) = Client::new(options, constants::MQTT_QUEUE_SIZE);
+    let (sender, events) = mpsc::channel();
+    thread::spawn(move || {
+        // Iterating would reconnect at once after errors, the next publish connects again
+        for event in connection.iter() {
+            let failed = event.is_err();
+            if sender.send(event).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    match events.recv_timeout(timeout) {
+        Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => {
+            info!("Connected to MQTT broker {}", mqtt_cfg.address);
+        }
+        Ok(Ok(event)) => bail!("unexpected response from {}: {:?}", mqtt_cfg.address, event),
+        Ok(Err(e)) => bail!("can't connect to {}: {}", mqtt_cfg.address, e),
+        Err(_) => bail!("timeout while connecting to {}", mqtt_cfg.address),
+    };
+    Ok(Session {
+        mqtt_cfg: mqtt_cfg.clone(),
+        client,
+        events,
+    })
+}
+
+// Publish all messages and wait until the broker has acknowledged them, the connection is
+// kept open between updates
+pub fn publish(
+    cfg: &config::Configuration,
+    mqtt_cfg: &config::MqttConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let messages = messages(mqtt_cfg);
+    if messages.is_empty() {
+        debug!("No weather data to publish to MQTT broker");
+        return Ok(());
+    }
+
+    let timeout = Duration::from_secs(cfg.timeout.unwrap_or(constants::HTTP_CLIENT_TIMEOUT));
+    let mut session = SESSION.lock().unwrap();
+    if session.as_ref().is_some_and(|v| v.mqtt_cfg != *mqtt_cfg) {
+        info!("Configuration of MQTT broker has changed, reconnecting");
+        *session = None;
+    }
+    if !session.as_ref().is_some_and(|v| v.is_alive()) {
+        *session = None;
+    }
+    let current = match session.as_mut() {
+        Some(v) => v,
+        None => session.insert(connect(mqtt_cfg, timeout)?),
+    };
+
+    debug!(
+        "Publishing {} messages to MQTT broker {}",
+        messages.len(),
+        mqtt_cfg.address
+    );
+    let qos = qos(mqtt_cfg);
+    let count = messages.len();
+    for (topic, payload) in messages {
+        current.client.publish(topic, qos, true, payload)?;
+    }
+
+    // Messages are sent with QoS 0, acknowledged with QoS 1 and completed with QoS 2
+    let deadline = Instant::now() + timeout;
+    let mut done = 0;
+    while done < count {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let event = match current.events.recv_timeout(remaining) {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => {
+                *session = None;
+                bail!("connection to {} failed: {}", mqtt_cfg.address, e);
+            }
+            Err(_) => {
+                *session = None;
+                bail!(
+                    "timeout while publishing to {}, {} of {} messages confirmed",
+                    mqtt_cfg.address,
+                    done,
+                    count
+                );
+            }
+        };
+        done += match (qos, event) {
+            (QoS::AtMostOnce, Event::Outgoing(Outgoing::Publish(_)))
+            | (QoS::AtLeastOnce, Event::Incoming(Packet::PubAck(_)))
+            | (QoS::ExactlyOnce, Event::Incoming(Packet::PubComp(_))) => 1,
+            _ => 0,
+        };
+    }
+    debug!("Published {} messages to MQTT broker", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn mqtt_configuration(yaml: &str) -> config::MqttConfiguration {
+        let address = if yaml.starts_with("address:") {
+            ""
+        } else {
+            "address: localhost:1883\n"
+        };
+        serde_yaml::from_str(&format!("{}{}", address, yaml)).unwrap()
+    }
+
+    // Minimal MQTT 3.1.1 broker, returns its address and the number of connections and
+    // received messages
+    fn fake_broker(acknowledge: bool) -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let messages = Arc::new(AtomicUsize::new(0));
+        let (connection_count, message_count) = (connections.clone(), messages.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                connection_count.fetch_add(1, Ordering::SeqCst);
+                let message_count = message_count.clone();
+                thread::spawn(move || serve_fake_broker(stream, acknowledge, &message_count));
+            }
+        });
+        (address, connections, messages)
+    }
+
+    fn serve_fake_broker(mut stream: TcpStream, acknowledge: bool, messages: &AtomicUsize) {
+        loop {
+            let mut header = [0; 1];
+            if stream.read_exact(&mut header).is_err() {
+                return;
+            }
+            let (mut length, mut shift) = (0, 0);
+            loop {
+                let mut byte = [0; 1];
+                stream.read_exact(&mut byte).unwrap();
+                length |= ((byte[0] & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte[0] & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+
+            let reply = match header[0] >> 4 {
+                // CONNECT, PINGREQ
+                1 => vec![0x20, 0x02, 0x00, 0x00],
+                12 => vec![0xd0, 0x00],
+                // PUBLISH is answered with PUBACK or PUBREC depending on its QoS, PUBREL with
+                // PUBCOMP
+                3 => {
+                    messages.fetch_add(1, Ordering::SeqCst);
+                    let qos = (header[0] >> 1) & 0x03;
+                    let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let id = &body[2 + topic_length..4 + topic_length];
+                    match qos {
+                        1 => vec![0x40, 0x02, id[0], id[1]],
+                        2 => vec![0x50, 0x02, id[0], id[1]],
+                        _ => continue,
+                    }
+                }
+                6 => vec![0x70, 0x02, body[0], body[1]],
+                _ => return,
+            };
+            if acknowledge || header[0] >> 4 != 3 {
+                stream.write_all(&reply).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let data =
+            serde_json::from_str(r#"{"name": "MQTT Publish Test", "main": {"temp": 20}}"#).unwrap();
+        exporter::set_location_metrics("MQTT Publish Test", &data);
+        let cfg = config::test_configuration("timeout: 2");
+        let (address, connections, messages) = fake_broker(true);
+
+        // The connection is kept for the next update
+        let mqtt_cfg = mqtt_configuration(&format!("address: {}\nqos: 1", address));
+        publish(&cfg, &mqtt_cfg).unwrap();
+        let published = messages.load(Ordering::SeqCst);
+        assert!(published > 0);
+        publish(&cfg, &mqtt_cfg).unwrap();
+        assert!(messages.load(Ordering::SeqCst) >= published * 2);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let mqtt_cfg = mqtt_configuration(&format!("address: {}\nqos: 2", address));
+        publish(&cfg, &mqtt_cfg).unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // Messages that aren't acknowledged in time are an error
+        let (address, _, _) = fake_broker(false);
+        let mqtt_cfg = mqtt_configuration(&format!("address: {}\nqos: 1", address));
+        let error = publish(&cfg, &mqtt_cfg).unwrap_err().to_string();
+        assert!(error.starts_with("timeout while publishing"), "{}", error);
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("London,GB"), "london_gb");
+        assert_eq!(sanitize("lat=52.5,lon=13.4"), "lat_52_5_lon_13_4");
+    }
+
+    #[test]
+    fn test_messages() {
+        let data = serde_json::from_str(
+            r#"{"dt": 1700000000, "name": "MQTT Test", "main": {"temp": 21.5, "humidity": 40}}"#,
+        )
+        .unwrap();
+        exporter::set_location_metrics("MQTT Test", &data);
+
+        let messages = messages(&mqtt_configuration("topic_prefix: weather"));
+        let message = |topic: &str| {
+            messages
+                .iter()
+                .find(|(t, _)| t == topic)
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(
+            message("weather/mqtt_test/temperature").as_deref(),
+            Some("21.5")
+        );
+        let document: serde_json::Value =
+            serde_json::from_str(&message("weather/mqtt_test").unwrap()).unwrap();
+        assert_eq!(document["name"], "MQTT Test");
+        assert_eq!(document["humidity"], 0.4);
+        assert_eq!(document["timestamp"], 1700000000);
+        assert!(messages
+            .iter()
+            .all(|(t, _)| !t.starts_with("homeassistant/")));
+    }
+
+    #[test]
+    fn test_messages_discovery() {
+        let data =
+            serde_json::from_str(r#"{"name": "MQTT Discovery Test", "main": {"humidity": 40}}"#)
+                .unwrap();
+        exporter::set_location_metrics("MQTT Discovery Test", &data);
+
+        let messages = messages(&mqtt_configuration("homeassistant_discovery: true"));
+        let (_, payload) = messages
+            .iter()
+            .find(|(t, _)| {
+                t == "homeassistant/sensor/openweathermap_mqtt_discovery_test_humidity/config"
+            })
+            .unwrap();
+        let discovery: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(
+            discovery["state_topic"],
+            "openweathermap/mqtt_discovery_test/humidity"
+        );
+        assert_eq!(discovery["device_class"], "humidity");
+        assert_eq!(discovery["unit_of_measurement"], "%");
+        assert!(discovery["value_template"].is_string());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config;
+use crate::exporter;
+use crate::outputs;
+
+use log::{info, warn};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+// Update all locations once and print the metrics or write them to a textfile
+pub fn run(cfg: &config::Configuration, textfile: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let failed = exporter::refresh(cfg).failed;
+    outputs::publish(cfg);
+    if failed > 0 {
+        warn!(
+            "{} of {} locations couldn't be updated",
+            failed,
+            cfg.locations.len()
+        );
+    }
+
+    match textfile {
+        Some(f) => {
+            write_textfile(f, &exporter::encode_metrics(false))?;
+            info!("Metrics written to {}", f);
+        }
+        None => print!("{}", exporter::encode_metrics(true)),
+    };
+
+    if failed == cfg.locations.len() {
+        bail!("no location could be updated");
+    }
+    Ok(())
+}
+
+// Write to a temporary file in the same directory and rename it, so the textfile collector
+// of the node exporter never reads incomplete data
+fn write_textfile(f: &str, metrics: &str) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(f);
+    let file_name = match path.file_name() {
+        Some(v) => v.to_string_lossy(),
+        None => bail!("{} is not a file name", f),
+    };
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+
+    if let Err(e) = fs::write(&tmp, metrics) {
+        bail!("can't write {}: {}", tmp.display(), e);
+    }
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        bail!("can't rename {} to {}: {}", tmp.display(), f, e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_textfile() {
+        let dir =
+            std::env::temp_dir().join(format!("openweathermap-exporter-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let f = dir.join("weather.prom");
+        let f = f.to_str().unwrap();
+
+        write_textfile(f, "first\n").unwrap();
+        write_textfile(f, "second\n").unwrap();
+        assert_eq!(fs::read_to_string(f).unwrap(), "second\n");
+        // The temporary file is renamed, so nothing else is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(write_textfile(dir.join("missing/weather.prom").to_str().unwrap(), "").is_err());
+        assert!(write_textfile("/", "").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use crate::constants;
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::fmt::Write;
+
+// Exposition formats served at the metrics endpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    OpenMetrics,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => constants::TEXT_FORMAT_CONTENT_TYPE,
+            Format::OpenMetrics => constants::OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+}
+
+// Use OpenMetrics if it is accepted with at least the same quality as the text format,
+// e.g. "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"
+pub fn negotiate(accept: &str) -> Format {
+    let mut openmetrics_quality = 0.0;
+    let mut text_quality = 0.0;
+
+    for media_range in accept.split(',') {
+        let mut parts = media_range.split(';').map(|v| v.trim());
+        let media_type = parts.next().unwrap_or_default().to_lowercase();
+        let mut quality = 1.0;
+        let mut version = None;
+        for parameter in parts {
+            if let Some((name, value)) = parameter.split_once('=') {
+                match name.trim().to_lowercase().as_str() {
+                    "q" => quality = value.trim().parse().unwrap_or(0.0),
+                    "version" => version = Some(value.trim().to_string()),
+                    _ => {}
+                };
+            }
+        }
+
+        match media_type.as_str() {
+            "application/openmetrics-text"
+                if version.is_none()
+                    || version.as_deref() == Some(constants::OPENMETRICS_VERSION) =>
+            {
+                openmetrics_quality = f64::max(openmetrics_quality, quality)
+            }
+            "text/plain" | "text/*" | "*/*" => text_quality = f64::max(text_quality, quality),
+            _ => {}
+        };
+    }
+
+    if openmetrics_quality > 0.0 && openmetrics_quality >= text_quality {
+        Format::OpenMetrics
+    } else {
+        Format::Text
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 {
+            "+Inf".to_string()
+        } else {
+            "-Inf".to_string()
+        }
+    } else {
+        value.to_string()
+    }
+}
+
+// The unit is only announced if it is the suffix of the metric name
+fn unit(name: &str) -> Option<&'static str> {
+    constants::OPENMETRICS_UNITS
+        .iter()
+        .find(|unit| name.ends_with(&format!("_{}", unit)))
+        .copied()
+}
+
+fn write_sample(
+    buffer: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+    timestamp_ms: i64,
+) {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|l| format!("{}=\"{}\"", l.get_name(), escape(l.get_value())))
+        .collect();
+    if let Some((label, value)) = extra_label {
+        pairs.push(format!("{}=\"{}\"", label, escape(&value)));
+    }
+
+    buffer.push_str(name);
+    if !pairs.is_empty() {
+        let _ = write!(buffer, "{{{}}}", pairs.join(","));
+    }
+    let _ = write!(buffer, " {}", format_value(value));
+    if timestamp_ms != 0 {
+        let _ = write!(buffer, " {}", timestamp_ms as f64 / 1000.0);
+    }
+    buffer.push('\n');
+}
+
+// Encode metric families in the OpenMetrics 1.0 text format, without the final "# EOF"
+pub fn encode(metric_families: &[MetricFamily], buffer: &mut String) {
+    for mf in metric_families {
+        let metric_type = mf.get_field_type();
+        // Counter samples end with _total, the metric family name must not
+        let name = match metric_type {
+            MetricType::COUNTER => mf
+                .get_name()
+                .strip_suffix("_total")
+                .unwrap_or(mf.get_name()),
+            _ => mf.get_name(),
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+
+        let _ = writeln!(buffer, "# TYPE {} {}", name, type_name);
+        if let Some(unit) = unit(name) {
+            let _ = writeln!(buffer, "# UNIT {} {}", name, unit);
+        }
+        if !mf.get_help().is_empty() {
+            let _ = writeln!(buffer, "# HELP {} {}", name, escape(mf.get_help()));
+        }
+
+        for m in mf.get_metric() {
+            let labels = m.get_label();
+            let timestamp_ms = m.get_timestamp_ms();
+            match metric_type {
+                MetricType::COUNTER => write_sample(
+                    buffer,
+                    &format!("{}_total", name),
+                    labels,
+                    None,
+                    m.get_counter().get_value(),
+                    timestamp_ms,
+                ),
+                MetricType::GAUGE => write_sample(
+                    buffer,
+                    name,
+                    labels,
+                    None,
+                    m.get_gauge().get_value(),
+                    timestamp_ms,
+                ),
+                MetricType::UNTYPED => write_sample(
+                    buffer,
+                    name,
+                    labels,
+                    None,
+                    m.get_untyped().get_value(),
+                    timestamp_ms,
+                ),
+                MetricType::HISTOGRAM => {
+                    let histogram = m.get_histogram();
+                    let mut has_inf = false;
+                    for bucket in histogram.get_bucket() {
+                        has_inf |= bucket.get_upper_bound().is_infinite();
+                        write_sample(
+                            buffer,
+                            &format!("{}_bucket", name),
+                            labels,
+                            Some(("le", format_value(bucket.get_upper_bound()))),
+                            bucket.get_cumulative_count() as f64,
+                            timestamp_ms,
+                        );
+                    }
+                    if !has_inf {
+                        write_sample(
+                            buffer,
+                            &format!("{}_bucket", name),
+                            labels,
+                            Some(("le", "+Inf".to_string())),
+                            histogram.get_sample_count() as f64,
+                            timestamp_ms,
+                        );
+                    }
+                    write_sample(
+                        buffer,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        histogram.get_sample_sum(),
+                        timestamp_ms,
+                    );
+                    write_sample(
+                        buffer,
+                        &format!("{}_count", name),
+                        labels,
+                        None,
+                        histogram.get_sample_count() as f64,
+                        timestamp_ms,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = m.get_summary();
+                    for quantile in summary.get_quantile() {
+                        write_sample(
+                            buffer,
+                            name,
+                            labels,
+                            Some(("quantile", format_value(quantile.get_quantile()))),
+                            quantile.get_value(),
+                            timestamp_ms,
+                        );
+                    }
+                    write_sample(
+                        buffer,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        summary.get_sample_sum(),
+                        timestamp_ms,
+                    );
+                    write_sample(
+                        buffer,
+                        &format!("{}_count", name),
+                        labels,
+                        None,
+                        summary.get_sample_count() as f64,
+                        timestamp_ms,
+                    );
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{CounterVec, Gauge, Histogram, HistogramOpts, Opts, Registry};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(""), Format::Text);
+        assert_eq!(negotiate("text/plain"), Format::Text);
+        assert_eq!(negotiate("*/*"), Format::Text);
+        assert_eq!(
+            negotiate("application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            negotiate("application/openmetrics-text"),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            negotiate("application/openmetrics-text;q=0.5,text/plain"),
+            Format::Text
+        );
+        // Unsupported versions are ignored
+        assert_eq!(
+            negotiate("application/openmetrics-text;version=2.0.0"),
+            Format::Text
+        );
+    }
+
+    #[test]
+    fn test_unit() {
+        assert_eq!(unit("openweathermap_temperature_celsius"), Some("celsius"));
+        // The values don't match the unit of the name
+        assert_eq!(unit("openweathermap_wind_speed_meters_per_hour"), None);
+        assert_eq!(unit("openweathermap_humidity_percent"), None);
+        assert_eq!(
+            unit("openweathermap_probe_duration_seconds"),
+            Some("seconds")
+        );
+        assert_eq!(unit("openweathermap_probe_success"), None);
+        // Only suffixes separated by an underscore are units
+        assert_eq!(unit("openweathermap_kiloseconds"), None);
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(1.5), "1.5");
+        assert_eq!(format_value(3.0), "3");
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("São Paulo"), "São Paulo");
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let gauge = Gauge::new("test_temperature_celsius", "Temperature").unwrap();
+        let counter =
+            CounterVec::new(Opts::new("test_failures_total", "Failures"), &["location"]).unwrap();
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("test_duration_seconds", "Duration").buckets(vec![0.5, 1.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        gauge.set(21.5);
+        counter.with_label_values(&["Köln,\"de\""]).inc();
+        histogram.observe(0.75);
+
+        let mut buffer = String::new();
+        encode(&registry.gather(), &mut buffer);
+        assert_eq!(
+            buffer,
+            "# TYPE test_duration_seconds histogram\n\
+             # UNIT test_duration_seconds seconds\n\
+             # HELP test_duration_seconds Duration\n\
+             test_duration_seconds_bucket{le=\"0.5\"} 0\n\
+             test_duration_seconds_bucket{le=\"1\"} 1\n\
+             test_duration_seconds_bucket{le=\"+Inf\"} 1\n\
+             test_duration_seconds_sum 0.75\n\
+             test_duration_seconds_count 1\n\
+             # TYPE test_failures counter\n\
+             # HELP test_failures Failures\n\
+             test_failures_total{location=\"Köln,\\\"de\\\"\"} 1\n\
+             # TYPE test_temperature_celsius gauge\n\
+             # UNIT test_temperature_celsius celsius\n\
+             # HELP test_temperature_celsius Temperature\n\
+             test_temperature_celsius 21.5\n"
+        );
+    }
+}
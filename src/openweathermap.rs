@@ -1,56 +1,82 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::error::Error;
+use std::fmt;
 
 // Documentation of the data format -> https://openweathermap.org/weather-data#current
+// Only the exported fields are parsed. Stations don't always report every value, so
+// everything except the name is optional
 #[derive(Deserialize, Clone, Debug)]
 pub struct OpenWeatherMap {
-    pub base: String,
-    pub clouds: OpenWeatherMapClouds,
-    pub cod: u8,
-    pub coord: OpenWeatherMapCoordinates,
-    pub dt: i64,
-    pub id: u64,
+    pub clouds: Option<OpenWeatherMapClouds>,
+    pub dt: Option<i64>,
+    pub id: Option<u64>,
+    #[serde(default)]
     pub main: OpenWeatherMapMain,
     pub name: String,
     pub rain: Option<OpenWeatherMapRainOrSnow>,
     pub snow: Option<OpenWeatherMapRainOrSnow>,
-    pub sys: OpenWeatherMapSys,
-    pub timezone: i32,
-    pub visibility: i64,
-    pub weather: Vec<OpenWeatherMapWeather>,
+    pub sys: Option<OpenWeatherMapSys>,
+    #[serde(default)]
     pub wind: OpenWeatherMapWind,
 }
 
-#[derive(Deserialize, Clone, Debug)]
-pub struct OpenWeatherMapCoordinates {
-    pub lat: f64,
-    pub lon: f64,
-}
+impl OpenWeatherMap {
+    pub fn country(&self) -> &str {
+        match &self.sys {
+            Some(sys) => sys.country.as_deref().unwrap_or_default(),
+            None => "",
+        }
+    }
 
-#[derive(Deserialize, Clone, Debug)]
-pub struct OpenWeatherMapWeather {
-    pub description: String,
-    pub icon: String,
-    pub id: i64,
-    pub main: String,
+    // Reported values in the same units as the exported metrics, for outputs other than Prometheus
+    pub fn values(&self) -> Vec<(&'static str, f64)> {
+        let main = &self.main;
+        let rain = self.rain.as_ref();
+        let snow = self.snow.as_ref();
+
+        [
+            ("temperature", main.temp),
+            ("apparent_temperature", main.feels_like),
+            ("minimal_temperature", main.temp_min),
+            ("maximal_temperature", main.temp_max),
+            ("pressure", main.pressure.map(|v| 100.0 * v as f64)),
+            ("humidity", main.humidity.map(|v| v as f64 / 100.0)),
+            ("wind_speed", self.wind.speed),
+            ("wind_gust", self.wind.gust),
+            ("wind_direction", self.wind.deg.map(|v| v as f64)),
+            (
+                "cloud_coverage",
+                self.clouds
+                    .as_ref()
+                    .and_then(|v| v.all)
+                    .map(|v| v as f64 / 100.0),
+            ),
+            ("rain_last_hour", rain.and_then(|v| v.one_h)),
+            ("rain_last_three_hours", rain.and_then(|v| v.three_h)),
+            ("snow_last_hour", snow.and_then(|v| v.one_h)),
+            ("snow_last_three_hours", snow.and_then(|v| v.three_h)),
+        ]
+        .iter()
+        .filter_map(|(name, value)| value.map(|v| (*name, v)))
+        .collect()
+    }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct OpenWeatherMapMain {
-    pub feels_like: f64,
-    pub grnd_level: Option<i32>,
-    pub humidity: u8,
-    pub pressure: u32,
-    pub sea_level: Option<i32>,
-    pub temp: f64,
-    pub temp_max: f64,
-    pub temp_min: f64,
+    pub feels_like: Option<f64>,
+    pub humidity: Option<u8>,
+    pub pressure: Option<u32>,
+    pub temp: Option<f64>,
+    pub temp_max: Option<f64>,
+    pub temp_min: Option<f64>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct OpenWeatherMapWind {
-    pub deg: u16,
+    pub deg: Option<u16>,
     pub gust: Option<f64>,
-    pub speed: f64,
+    pub speed: Option<f64>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -63,15 +89,214 @@ pub struct OpenWeatherMapRainOrSnow {
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct OpenWeatherMapClouds {
-    pub all: u8,
+    pub all: Option<u8>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct OpenWeatherMapSys {
-    pub country: String,
-    pub id: i64,
-    #[serde(rename = "type")]
-    pub owm_type: i32,
-    pub sunrise: u64,
-    pub sunset: u64,
+    pub country: Option<String>,
+}
+
+// Error replies, e.g. {"cod":"404","message":"city not found"} or {"cod":401,"message":"Invalid API key. ..."}
+#[derive(Deserialize, Clone, Debug)]
+pub struct OpenWeatherMapErrorReply {
+    #[serde(deserialize_with = "deserialize_cod")]
+    pub cod: u16,
+    pub message: String,
+}
+
+// OpenWeatherMap reports the status code as number or as string, depending on the error
+fn deserialize_cod<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Cod {
+        Number(u16),
+        Text(String),
+    }
+
+    match Cod::deserialize(deserializer)? {
+        Cod::Number(v) => Ok(v),
+        Cod::Text(v) => v.trim().parse().map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiErrorKind {
+    InvalidApiKey,
+    UnknownLocation,
+    RateLimited,
+    ServerError,
+    ClientError,
+    Timeout,
+    Connection,
+}
+
+impl ApiErrorKind {
+    pub const ALL: [ApiErrorKind; 7] = [
+        ApiErrorKind::InvalidApiKey,
+        ApiErrorKind::UnknownLocation,
+        ApiErrorKind::RateLimited,
+        ApiErrorKind::ServerError,
+        ApiErrorKind::ClientError,
+        ApiErrorKind::Timeout,
+        ApiErrorKind::Connection,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiErrorKind::InvalidApiKey => "invalid_api_key",
+            ApiErrorKind::UnknownLocation => "unknown_location",
+            ApiErrorKind::RateLimited => "rate_limited",
+            ApiErrorKind::ServerError => "server_error",
+            ApiErrorKind::ClientError => "client_error",
+            ApiErrorKind::Timeout => "timeout",
+            ApiErrorKind::Connection => "connection",
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiErrorKind::RateLimited | ApiErrorKind::ServerError | ApiErrorKind::Timeout
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    pub kind: ApiErrorKind,
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn from_response(status: u16, body: &str) -> Self {
+        // Prefer the status code and message of the reply, fall back to the HTTP status
+        let (status, message) = match serde_json::from_str::<OpenWeatherMapErrorReply>(body) {
+            Ok(v) => (v.cod, v.message),
+            Err(_) => (status, body.trim().to_string()),
+        };
+
+        let kind = match status {
+            401 => ApiErrorKind::InvalidApiKey,
+            404 => ApiErrorKind::UnknownLocation,
+            429 => ApiErrorKind::RateLimited,
+            500..=599 => ApiErrorKind::ServerError,
+            _ => ApiErrorKind::ClientError,
+        };
+
+        ApiError {
+            kind,
+            status: Some(status),
+            message,
+        }
+    }
+
+    pub fn from_transport(e: &reqwest::Error) -> Self {
+        let kind = if e.is_timeout() {
+            ApiErrorKind::Timeout
+        } else {
+            ApiErrorKind::Connection
+        };
+
+        ApiError {
+            kind,
+            status: None,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self.kind {
+            ApiErrorKind::InvalidApiKey => "invalid API key",
+            ApiErrorKind::UnknownLocation => "unknown location",
+            ApiErrorKind::RateLimited => "rate limit exceeded",
+            ApiErrorKind::ServerError => "server error",
+            ApiErrorKind::ClientError => "request failed",
+            ApiErrorKind::Timeout => "request timed out",
+            ApiErrorKind::Connection => "connection failed",
+        };
+
+        match self.status {
+            Some(status) => write!(
+                f,
+                "{} (HTTP status {}): {}",
+                description, status, self.message
+            ),
+            None => write!(f, "{}: {}", description, self.message),
+        }
+    }
+}
+
+impl Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_from_response() {
+        let e = ApiError::from_response(404, r#"{"cod":"404","message":"city not found"}"#);
+        assert_eq!(e.kind, ApiErrorKind::UnknownLocation);
+        assert_eq!(e.status, Some(404));
+        assert_eq!(e.message, "city not found");
+        assert_eq!(
+            e.to_string(),
+            "unknown location (HTTP status 404): city not found"
+        );
+
+        let e = ApiError::from_response(401, r#"{"cod":401,"message":"Invalid API key."}"#);
+        assert_eq!(e.kind, ApiErrorKind::InvalidApiKey);
+        assert!(!e.kind.is_retryable());
+    }
+
+    #[test]
+    fn test_error_from_response_without_json() {
+        let e = ApiError::from_response(503, " Service Unavailable\n");
+        assert_eq!(e.kind, ApiErrorKind::ServerError);
+        assert_eq!(e.message, "Service Unavailable");
+        assert!(e.kind.is_retryable());
+
+        assert_eq!(
+            ApiError::from_response(429, "").kind,
+            ApiErrorKind::RateLimited
+        );
+        assert_eq!(
+            ApiError::from_response(400, "").kind,
+            ApiErrorKind::ClientError
+        );
+    }
+
+    #[test]
+    fn test_values() {
+        let data: OpenWeatherMap = serde_json::from_str(
+            r#"{"name": "Berlin", "main": {"temp": 21.5, "pressure": 1013, "humidity": 40},
+                "clouds": {"all": 75}, "rain": {"1h": 0.3}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            data.values(),
+            vec![
+                ("temperature", 21.5),
+                ("pressure", 101300.0),
+                ("humidity", 0.4),
+                ("cloud_coverage", 0.75),
+                ("rain_last_hour", 0.3),
+            ]
+        );
+        assert_eq!(data.country(), "");
+    }
+
+    #[test]
+    fn test_values_missing_fields() {
+        // Only the name is required
+        let data: OpenWeatherMap = serde_json::from_str(r#"{"name": "Berlin"}"#).unwrap();
+        assert!(data.values().is_empty());
+        assert!(serde_json::from_str::<OpenWeatherMap>(r#"{"main": {}}"#).is_err());
+    }
 }
@@ -0,0 +1,290 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::http;
+
+use log::debug;
+use prost::Message;
+use std::error::Error;
+
+// Subset of the OTLP metrics protocol, see
+// https://github.com/open-telemetry/opentelemetry-proto/tree/main/opentelemetry/proto
+#[derive(Clone, PartialEq, Message)]
+struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    version: String,
+}
+
+// Only the gauge member of the data oneof is used
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    description: String,
+    #[prost(string, tag = "3")]
+    unit: String,
+    #[prost(message, optional, tag = "5")]
+    gauge: Option<Gauge>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    data_points: Vec<NumberDataPoint>,
+}
+
+// as_double is part of a oneof and must be sent even if it is 0
+#[derive(Clone, PartialEq, Message)]
+struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    time_unix_nano: u64,
+    #[prost(double, optional, tag = "4")]
+    as_double: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct KeyValue {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(message, optional, tag = "2")]
+    value: Option<AnyValue>,
+}
+
+// Only the string_value member of the value oneof is used
+#[derive(Clone, PartialEq, Message)]
+struct AnyValue {
+    #[prost(string, tag = "1")]
+    string_value: String,
+}
+
+fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            string_value: value.to_string(),
+        }),
+    }
+}
+
+// Description and UCUM unit of the values of a location
+fn describe(value: &str) -> (&'static str, &'static str) {
+    match value {
+        "temperature" => (constants::METRIC_TEMP_HELP, "Cel"),
+        "apparent_temperature" => (constants::METRIC_TEMP_FEELS_LIKE_HELP, "Cel"),
+        "minimal_temperature" => (constants::METRIC_TEMP_MIN_HELP, "Cel"),
+        "maximal_temperature" => (constants::METRIC_TEMP_MAX_HELP, "Cel"),
+        "pressure" => (constants::METRIC_PRESSURE_HELP, "Pa"),
+        "humidity" => (constants::METRIC_HUMIDITY_HELP, "1"),
+        "wind_speed" => (constants::METRIC_WIND_SPEED_HELP, "m/s"),
+        "wind_gust" => (constants::METRIC_WIND_GUST_HELP, "m/s"),
+        "wind_direction" => (constants::METRIC_WIND_DIRECTION_HELP, "deg"),
+        "cloud_coverage" => (constants::METRIC_CLOUD_HELP, "1"),
+        "rain_last_hour" => (constants::METRIC_RAIN_1H_HELP, "mm"),
+        "rain_last_three_hours" => (constants::METRIC_RAIN_3H_HELP, "mm"),
+        "snow_last_hour" => (constants::METRIC_SNOW_1H_HELP, "mm"),
+        "snow_last_three_hours" => (constants::METRIC_SNOW_3H_HELP, "mm"),
+        _ => ("", ""),
+    }
+}
+
+fn collect() -> Vec<Metric> {
+    let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64;
+    let mut metrics: Vec<Metric> = Vec::new();
+
+    for (location, data) in exporter::observations() {
+        let mut attributes = vec![
+            key_value("location", &location),
+            key_value("name", &data.name),
+            key_value("country", data.country()),
+        ];
+        if let Some(id) = data.id {
+            attributes.push(key_value("id", &id.to_string()));
+        }
+        let time_unix_nano = data.dt.map(|v| v as u64 * 1_000_000_000).unwrap_or(now);
+
+        for (value_name, value) in data.values() {
+            let name = format!("{}.{}", constants::OTLP_METRIC_PREFIX, value_name);
+            let point = NumberDataPoint {
+                attributes: attributes.clone(),
+                time_unix_nano,
+                as_double: Some(value),
+            };
+
+            match metrics.iter_mut().find(|m| m.name == name) {
+                Some(metric) => {
+                    if let Some(gauge) = &mut metric.gauge {
+                        gauge.data_points.push(point);
+                    }
+                }
+                None => {
+                    let (description, unit) = describe(value_name);
+                    metrics.push(Metric {
+                        name,
+                        description: description.to_string(),
+                        unit: unit.to_string(),
+                        gauge: Some(Gauge {
+                            data_points: vec![point],
+                        }),
+                    });
+                }
+            };
+        }
+    }
+    metrics
+}
+
+fn resource(otlp_cfg: &config::OtlpConfiguration) -> Resource {
+    let mut attributes = vec![
+        key_value("service.name", constants::NAME),
+        key_value("service.version", constants::VERSION),
+    ];
+    for (key, value) in &otlp_cfg.resource_attributes {
+        attributes.retain(|v| &v.key != key);
+        attributes.push(key_value(key, value));
+    }
+    Resource { attributes }
+}
+
+// Send weather data to an OTLP/HTTP endpoint using binary protobuf encoding
+pub fn export(
+    cfg: &config::Configuration,
+    otlp_cfg: &config::OtlpConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let metrics = collect();
+    if metrics.is_empty() {
+        debug!("No weather data to send to OTLP endpoint");
+        return Ok(());
+    }
+
+    let request = ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(resource(otlp_cfg)),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: constants::NAME.to_string(),
+                    version: constants::VERSION.to_string(),
+                }),
+                metrics,
+            }],
+        }],
+    };
+
+    let client = http::build_client(cfg)?;
+    let mut request = client
+        .post(&otlp_cfg.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+        .body(request.encode_to_vec());
+    for (name, value) in &otlp_cfg.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    debug!("Sending weather data to {}", otlp_cfg.url);
+    let response = request.send()?;
+    if !response.status().is_success() {
+        bail!(
+            "OTLP endpoint returned HTTP status code \"{}\": {}",
+            response.status(),
+            response.text().unwrap_or_default().trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a str> {
+        attributes
+            .iter()
+            .find(|v| v.key == key)
+            .and_then(|v| v.value.as_ref())
+            .map(|v| v.string_value.as_str())
+    }
+
+    #[test]
+    fn test_collect() {
+        let data = serde_json::from_str(
+            r#"{"dt": 1700000000, "id": 3, "name": "OTLP Test", "main": {"temp": 21.5}}"#,
+        )
+        .unwrap();
+        exporter::set_location_metrics("otlp test", &data);
+
+        let metrics = collect();
+        let metric = metrics
+            .iter()
+            .find(|m| m.name == "openweathermap.temperature")
+            .unwrap();
+        assert_eq!(metric.unit, "Cel");
+        let point = metric
+            .gauge
+            .as_ref()
+            .unwrap()
+            .data_points
+            .iter()
+            .find(|p| attribute(&p.attributes, "location") == Some("otlp test"))
+            .unwrap();
+        assert_eq!(point.as_double, Some(21.5));
+        assert_eq!(point.time_unix_nano, 1_700_000_000_000_000_000);
+        assert_eq!(attribute(&point.attributes, "name"), Some("OTLP Test"));
+        assert_eq!(attribute(&point.attributes, "id"), Some("3"));
+
+        // Every metric is only reported once, with a data point per location
+        let mut names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), metrics.len());
+    }
+
+    #[test]
+    fn test_resource() {
+        let otlp_cfg: config::OtlpConfiguration = serde_yaml::from_str(
+            "url: http://localhost:4318/v1/metrics\nresource_attributes: {service.name: weather, host.name: a}",
+        )
+        .unwrap();
+        let resource = resource(&otlp_cfg);
+        assert_eq!(
+            attribute(&resource.attributes, "service.name"),
+            Some("weather")
+        );
+        assert_eq!(
+            attribute(&resource.attributes, "service.version"),
+            Some(constants::VERSION)
+        );
+        assert_eq!(attribute(&resource.attributes, "host.name"), Some("a"));
+        assert_eq!(resource.attributes.len(), 3);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::graphite;
+use crate::influxdb;
+use crate::mqtt;
+use crate::otlp;
+use crate::pushgateway;
+use crate::remote_write;
+
+use log::{debug, error};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+// Outputs that send data after each refresh instead of waiting for scrapes. Line protocol at
+// /influx is served from the data of these refreshes, even without InfluxDB url
+pub fn enabled(cfg: &config::Configuration) -> bool {
+    cfg.pushgateway.is_some()
+        || cfg.remote_write.is_some()
+        || cfg.influxdb.is_some()
+        || cfg.graphite.is_some()
+        || cfg.otlp.is_some()
+        || cfg.mqtt.is_some()
+}
+
+pub fn publish(cfg: &config::Configuration) {
+    if let Some(pushgateway_cfg) = &cfg.pushgateway {
+        if let Err(e) = pushgateway::push(cfg, pushgateway_cfg) {
+            error!("Can't push metrics to Pushgateway: {}", e);
+        }
+    }
+
+    if let Some(remote_write_cfg) = &cfg.remote_write {
+        if let Err(e) = remote_write::write(cfg, remote_write_cfg) {
+            error!("Can't send metrics to remote write endpoint: {}", e);
+        }
+    }
+
+    if let Some(influxdb_cfg) = &cfg.influxdb {
+        if let Err(e) = influxdb::write(cfg, influxdb_cfg) {
+            error!("Can't write weather data to InfluxDB: {}", e);
+        }
+    }
+
+    if let Some(graphite_cfg) = &cfg.graphite {
+        if let Err(e) = graphite::send(cfg, graphite_cfg) {
+            error!("Can't send weather data to carbon server: {}", e);
+        }
+    }
+
+    if let Some(otlp_cfg) = &cfg.otlp {
+        if let Err(e) = otlp::export(cfg, otlp_cfg) {
+            error!("Can't send weather data to OTLP endpoint: {}", e);
+        }
+    }
+
+    if let Some(mqtt_cfg) = &cfg.mqtt {
+        if let Err(e) = mqtt::publish(cfg, mqtt_cfg) {
+            error!("Can't publish weather data to MQTT broker: {}", e);
+        }
+    }
+}
+
+// Refresh locations when they are due and publish the data to all outputs
+pub fn run_background(shared_cfg: Arc<RwLock<config::Configuration>>) {
+    thread::spawn(move || loop {
+        // The configuration isn't locked during the update, so it doesn't block a reload
+        let cfg = shared_cfg.read().unwrap().clone();
+        if enabled(&cfg) {
+            let summary = exporter::refresh(&cfg);
+            if summary.updated > 0 {
+                debug!(
+                    "Publishing weather data of {} updated locations",
+                    summary.updated
+                );
+                publish(&cfg);
+            }
+            if let Some(remote_write_cfg) = &cfg.remote_write {
+                if let Err(e) = remote_write::retry(&cfg, remote_write_cfg) {
+                    error!("Can't send queued metrics to remote write endpoint: {}", e);
+                }
+            }
+        }
+        thread::sleep(Duration::from_secs(constants::BACKGROUND_REFRESH_TICK));
+    });
+}
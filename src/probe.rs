@@ -0,0 +1,217 @@
+use crate::budget;
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::http;
+use crate::openmetrics;
+
+use log::{debug, error, warn};
+use prometheus::{Gauge, Registry};
+use std::error::Error;
+use std::time::Instant;
+
+// Target of a probe, given by location=<name>, id=<city ID> or lat=<latitude>&lon=<longitude>
+pub fn parse_target(query: &[(String, String)]) -> Result<config::Location, Box<dyn Error>> {
+    let parameter = |name: &str| {
+        query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let location = match (
+        parameter("location"),
+        parameter("id"),
+        parameter("lat"),
+        parameter("lon"),
+    ) {
+        (Some(name), None, None, None) => config::Location::Name(name.to_string()),
+        (None, Some(id), None, None) => match id.parse() {
+            Ok(id) => config::Location::Id { id },
+            Err(_) => bail!("invalid city ID {}", id),
+        },
+        (None, None, Some(lat), Some(lon)) => match (lat.parse(), lon.parse()) {
+            (Ok(lat), Ok(lon)) => config::Location::Coordinates { lat, lon },
+            _ => bail!("invalid coordinates {},{}", lat, lon),
+        },
+        _ => bail!("either location, id or lat and lon must be given"),
+    };
+
+    if let Some(message) = config::validate_location(&location).first() {
+        bail!("{}", message);
+    }
+    Ok(location)
+}
+
+// Names are compared case-insensitive, as for duplicate locations in the configuration.
+// Without allowed locations, probes are only allowed if explicitly enabled
+pub fn is_allowed(probe_cfg: &config::ProbeConfiguration, location: &config::Location) -> bool {
+    match &probe_cfg.allowed_locations {
+        Some(allowed) => {
+            let location = location.to_string().to_lowercase();
+            allowed
+                .iter()
+                .any(|v| v.to_string().to_lowercase() == location)
+        }
+        None => probe_cfg.allow_all_locations.unwrap_or(false),
+    }
+}
+
+fn fetch(
+    cfg: &config::Configuration,
+    location: &config::Location,
+    weather: &exporter::WeatherMetrics,
+    deadline: Option<Instant>,
+) -> bool {
+    if !budget::acquire() {
+        warn!(
+            "API call budget exhausted, skipping probe of weather data for {}",
+            location
+        );
+        exporter::BUDGET_SKIPPED.inc();
+        return false;
+    }
+
+    let mut client = match http::build_api_client(cfg) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Can't build HTTP client structure: {}", e);
+            return false;
+        }
+    };
+    match exporter::fetch_location(&mut client, cfg, location, deadline) {
+        Ok(data) => {
+            weather.set(&data);
+            true
+        }
+        Err(e) => {
+            error!("Can't probe weather data for {}: {}", location, e);
+            false
+        }
+    }
+}
+
+// Weather data of the target is fetched on every probe and returned in a registry of its own,
+// so it doesn't show up at the metrics endpoint
+pub fn probe(
+    cfg: &config::Configuration,
+    location: &config::Location,
+    format: openmetrics::Format,
+    deadline: Option<Instant>,
+) -> String {
+    debug!("Probing weather data for {}", location);
+
+    let registry = Registry::new();
+    let weather = exporter::WeatherMetrics::new(false);
+    let success = Gauge::new(
+        constants::METRIC_PROBE_SUCCESS_NAME,
+        constants::METRIC_PROBE_SUCCESS_HELP,
+    )
+    .unwrap();
+    let duration = Gauge::new(
+        constants::METRIC_PROBE_DURATION_NAME,
+        constants::METRIC_PROBE_DURATION_HELP,
+    )
+    .unwrap();
+    weather.register(&registry).unwrap();
+    registry.register(Box::new(success.clone())).unwrap();
+    registry.register(Box::new(duration.clone())).unwrap();
+
+    let start = Instant::now();
+    if fetch(cfg, location, &weather, deadline) {
+        success.set(1.0);
+    }
+    duration.set(start.elapsed().as_secs_f64());
+    budget::update_metrics();
+
+    let mut buffer = String::new();
+    match format {
+        openmetrics::Format::Text => {
+            let encoder = prometheus::TextEncoder::new();
+            if let Err(e) = encoder.encode_utf8(&registry.gather(), &mut buffer) {
+                error!("Can't encode metrics as UTF8 string: {}", e);
+            }
+        }
+        openmetrics::Format::OpenMetrics => {
+            openmetrics::encode(&registry.gather(), &mut buffer);
+            buffer.push_str("# EOF\n");
+        }
+    };
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(parameters: &[(&str, &str)]) -> Vec<(String, String)> {
+        parameters
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn probe_configuration(yaml: &str) -> config::ProbeConfiguration {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target(&query(&[("location", "London,gb")])).unwrap(),
+            config::Location::Name("London,gb".to_string())
+        );
+        assert_eq!(
+            parse_target(&query(&[("id", "2950159")])).unwrap(),
+            config::Location::Id { id: 2950159 }
+        );
+        assert_eq!(
+            parse_target(&query(&[("lat", "52.52"), ("lon", "13.41")])).unwrap(),
+            config::Location::Coordinates {
+                lat: 52.52,
+                lon: 13.41
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_target_invalid() {
+        assert!(parse_target(&query(&[])).is_err());
+        assert!(parse_target(&query(&[("location", " ")])).is_err());
+        assert!(parse_target(&query(&[("id", "Berlin")])).is_err());
+        assert!(parse_target(&query(&[("lat", "52.52")])).is_err());
+        assert!(parse_target(&query(&[("lat", "91"), ("lon", "13.41")])).is_err());
+        assert!(parse_target(&query(&[("location", "Berlin"), ("id", "2950159")])).is_err());
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let probe_cfg = probe_configuration("allowed_locations: [Berlin, {id: 2950159}]");
+        assert!(is_allowed(
+            &probe_cfg,
+            &config::Location::Name("BERLIN".to_string())
+        ));
+        assert!(is_allowed(
+            &probe_cfg,
+            &config::Location::Id { id: 2950159 }
+        ));
+        assert!(!is_allowed(
+            &probe_cfg,
+            &config::Location::Name("London".to_string())
+        ));
+    }
+
+    #[test]
+    fn test_is_allowed_without_allowed_locations() {
+        let location = config::Location::Name("Berlin".to_string());
+        assert!(!is_allowed(&probe_configuration("{}"), &location));
+        assert!(!is_allowed(
+            &probe_configuration("allow_all_locations: false"),
+            &location
+        ));
+        assert!(is_allowed(
+            &probe_configuration("allow_all_locations: true"),
+            &location
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::http;
+
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use log::debug;
+use std::error::Error;
+
+// Label values containing a "/" (or other characters with a special meaning in URLs)
+// must be base64 encoded, see https://github.com/prometheus/pushgateway#url
+fn encode_label(name: &str, value: &str) -> String {
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        format!("{}@base64/{}", name, URL_SAFE.encode(value))
+    } else {
+        format!("{}/{}", name, value)
+    }
+}
+
+pub fn push(
+    cfg: &config::Configuration,
+    pushgateway_cfg: &config::PushgatewayConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let job = pushgateway_cfg
+        .job
+        .as_deref()
+        .unwrap_or(constants::DEFAULT_PUSHGATEWAY_JOB);
+
+    let mut url = format!(
+        "{}/metrics/{}",
+        pushgateway_cfg.url.trim_end_matches('/'),
+        encode_label("job", job)
+    );
+    for (name, value) in &pushgateway_cfg.grouping {
+        url.push('/');
+        url.push_str(&encode_label(name, value));
+    }
+
+    let body = exporter::encode_metrics(false);
+    let client = http::build_client(cfg)?;
+    let request = match pushgateway_cfg.method {
+        config::PushMethod::Put => client.put(&url),
+        config::PushMethod::Post => client.post(&url),
+    };
+    let mut request = request
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            constants::TEXT_FORMAT_CONTENT_TYPE,
+        )
+        .body(body);
+    if let (Some(user), Some(pass)) = (&pushgateway_cfg.username, &pushgateway_cfg.password) {
+        request = request.basic_auth(user, Some(pass));
+    }
+
+    debug!("Pushing metrics to {}", url);
+    let response = request.send()?;
+    if !response.status().is_success() {
+        bail!(
+            "Pushgateway returned HTTP status code \"{}\": {}",
+            response.status(),
+            response.text().unwrap_or_default().trim()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_label() {
+        assert_eq!(encode_label("job", "openweathermap"), "job/openweathermap");
+        assert_eq!(
+            encode_label("instance", "host-1.example_a"),
+            "instance/host-1.example_a"
+        );
+        assert_eq!(encode_label("path", "/var/tmp"), "path@base64/L3Zhci90bXA=");
+        assert_eq!(encode_label("city", "Zürich"), "city@base64/WsO8cmljaA==");
+    }
+}
@@ -0,0 +1,166 @@
+use crate::budget;
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::schedule;
+
+use log::{debug, error, info, warn};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+pub fn set_reload_metrics(success: bool) {
+    if success {
+        exporter::CONFIG_LAST_RELOAD_SUCCESSFUL.set(1);
+        exporter::CONFIG_LAST_RELOAD_SUCCESS_TIMESTAMP.set(chrono::Utc::now().timestamp());
+    } else {
+        exporter::CONFIG_LAST_RELOAD_SUCCESSFUL.set(0);
+    }
+}
+
+pub fn reload(config_file: &str, shared_cfg: &Arc<RwLock<config::Configuration>>) {
+    info!("Reloading configuration from {}", config_file);
+
+    match apply(config_file, shared_cfg) {
+        Ok(_) => {
+            info!("Configuration reloaded");
+            set_reload_metrics(true);
+        }
+        Err(e) => {
+            error!(
+                "Can't reload configuration, keeping previous configuration: {}",
+                e
+            );
+            set_reload_metrics(false);
+        }
+    };
+}
+
+fn apply(
+    config_file: &str,
+    shared_cfg: &Arc<RwLock<config::Configuration>>,
+) -> Result<(), Box<dyn Error>> {
+    let new_cfg = config::parse_config_file(config_file)?;
+
+    // Block scrapes until the new configuration is completely applied
+    let mut cfg = shared_cfg.write().unwrap();
+
+    if new_cfg.listen != cfg.listen {
+        warn!("Changing the listen address requires a restart");
+    }
+
+    budget::init(&new_cfg)?;
+    schedule::init(&new_cfg);
+
+    let configured: HashSet<String> = new_cfg.locations.iter().map(|l| l.to_string()).collect();
+    let previous: HashSet<String> = cfg.locations.iter().map(|l| l.to_string()).collect();
+    for location in previous.difference(&configured) {
+        info!("Location {} was removed", location);
+        exporter::remove_location(location);
+    }
+    for location in configured.difference(&previous) {
+        exporter::add_location(location);
+    }
+
+    *cfg = new_cfg;
+    Ok(())
+}
+
+// Reload the configuration on SIGHUP and, if enabled, when the file was modified
+pub fn watch(config_file: &str, shared_cfg: Arc<RwLock<config::Configuration>>) {
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Can't install signal handler for SIGHUP: {}", e);
+            return;
+        }
+    };
+
+    let file = config_file.to_string();
+    let cfg = shared_cfg.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            reload(&file, &cfg);
+        }
+    });
+
+    // watch_config can be changed by a reload, so it is checked every time
+    let file = config_file.to_string();
+    thread::spawn(move || {
+        let mut last_modified = modification_time(&file);
+        loop {
+            thread::sleep(Duration::from_secs(constants::CONFIG_WATCH_INTERVAL));
+            let modified = modification_time(&file);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            if shared_cfg.read().unwrap().watch_config.unwrap_or(false) {
+                debug!("Configuration file {} was modified", file);
+                reload(&file, &shared_cfg);
+            }
+        }
+    });
+}
+
+fn modification_time(f: &str) -> Option<SystemTime> {
+    fs::metadata(f).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(yaml: &str) -> String {
+        let f = std::env::temp_dir().join(format!(
+            "openweathermap-exporter-test-{}-reload.yml",
+            std::process::id()
+        ));
+        fs::write(&f, yaml).unwrap();
+        f.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_reload() {
+        let f = write_config(
+            "api_key: x\nlocations: [Reload Test A, Reload Test B]\nrefresh_interval: 600\nbudget: {calls_per_day: 100000}\n",
+        );
+        let cfg = config::parse_config_file(&f).unwrap();
+        budget::init(&cfg).unwrap();
+        schedule::init(&cfg);
+        assert!(budget::acquire());
+        schedule::done("Reload Test A");
+        let data = serde_json::from_str(r#"{"name": "Reload Test B"}"#).unwrap();
+        exporter::set_location_metrics("Reload Test B", &data);
+        let shared_cfg = Arc::new(RwLock::new(cfg));
+
+        write_config(
+            "api_key: x\nlocations: [Reload Test A]\nrefresh_interval: 600\nbudget: {calls_per_day: 100000}\n",
+        );
+        reload(&f, &shared_cfg);
+        assert_eq!(exporter::CONFIG_LAST_RELOAD_SUCCESSFUL.get(), 1);
+        assert_eq!(shared_cfg.read().unwrap().locations.len(), 1);
+        assert!(exporter::observations()
+            .iter()
+            .all(|(k, _)| k != "Reload Test B"));
+
+        // Schedule and budget are kept
+        assert!(!schedule::is_due("Reload Test A"));
+        assert!(exporter::BUDGET_REMAINING.with_label_values(&["day"]).get() < 100000);
+
+        // Invalid configurations are not applied
+        write_config("api_key: ''\nlocations: [Reload Test C]\n");
+        reload(&f, &shared_cfg);
+        assert_eq!(exporter::CONFIG_LAST_RELOAD_SUCCESSFUL.get(), 0);
+        assert_eq!(
+            shared_cfg.read().unwrap().locations[0].to_string(),
+            "Reload Test A"
+        );
+        fs::remove_file(&f).unwrap();
+    }
+}
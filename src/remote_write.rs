@@ -0,0 +1,280 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::http;
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use prometheus::proto::MetricType;
+use prost::Message;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // Batches that couldn't be sent yet, oldest first
+    static ref QUEUE: Mutex<VecDeque<Vec<TimeSeries>>> = Mutex::new(VecDeque::new());
+    // Time of the next attempt to send queued batches and the current delay after a failure
+    static ref BACKOFF: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+}
+
+// Subset of the remote write protocol, see
+// https://github.com/prometheus/prometheus/blob/main/prompb/remote.proto
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+fn collect(remote_write_cfg: &config::RemoteWriteConfiguration) -> Vec<TimeSeries> {
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    let mut result = Vec::new();
+
+    // Only the weather data is sent, metrics of the exporter itself are left to scrapes
+    for mf in exporter::gather_weather() {
+        for m in mf.get_metric() {
+            let value = match mf.get_field_type() {
+                MetricType::GAUGE => m.get_gauge().get_value(),
+                MetricType::COUNTER => m.get_counter().get_value(),
+                MetricType::UNTYPED => m.get_untyped().get_value(),
+                _ => continue,
+            };
+
+            let mut labels = vec![Label {
+                name: "__name__".to_string(),
+                value: mf.get_name().to_string(),
+            }];
+            for l in m.get_label() {
+                labels.push(Label {
+                    name: l.get_name().to_string(),
+                    value: l.get_value().to_string(),
+                });
+            }
+            for (name, value) in &remote_write_cfg.labels {
+                labels.push(Label {
+                    name: name.clone(),
+                    value: value.clone(),
+                });
+            }
+            // Remote write requires labels sorted by name
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+            result.push(TimeSeries {
+                labels,
+                samples: vec![Sample { value, timestamp }],
+            });
+        }
+    }
+    result
+}
+
+// Returns Ok(false) if the batch was rejected and must not be retried
+fn send(
+    client: &reqwest::blocking::Client,
+    remote_write_cfg: &config::RemoteWriteConfiguration,
+    timeseries: &[TimeSeries],
+) -> Result<bool, Box<dyn Error>> {
+    let request = WriteRequest {
+        timeseries: timeseries.to_vec(),
+    };
+    let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?;
+
+    let mut request = client
+        .post(&remote_write_cfg.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+        .header(reqwest::header::CONTENT_ENCODING, "snappy")
+        .header(
+            "X-Prometheus-Remote-Write-Version",
+            constants::REMOTE_WRITE_VERSION,
+        )
+        .body(body);
+    if let (Some(user), Some(pass)) = (&remote_write_cfg.username, &remote_write_cfg.password) {
+        request = request.basic_auth(user, Some(pass));
+    }
+    if let Some(token) = &remote_write_cfg.bearer_token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send()?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(true);
+    }
+
+    let message = response.text().unwrap_or_default();
+    // Client errors (except rate limiting) will fail again
+    if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        warn!(
+            "Remote write endpoint rejected data with HTTP status code \"{}\", dropping it: {}",
+            status,
+            message.trim()
+        );
+        return Ok(false);
+    }
+
+    bail!(
+        "remote write endpoint returned HTTP status code \"{}\": {}",
+        status,
+        message.trim()
+    );
+}
+
+// Send queued batches, oldest first, and delay the next attempt if one fails
+fn flush(
+    cfg: &config::Configuration,
+    remote_write_cfg: &config::RemoteWriteConfiguration,
+    queue: &mut VecDeque<Vec<TimeSeries>>,
+) -> Result<(), Box<dyn Error>> {
+    let client = http::build_client(cfg)?;
+    let mut result = Ok(());
+    while let Some(timeseries) = queue.front() {
+        debug!(
+            "Sending {} time series to {}",
+            timeseries.len(),
+            remote_write_cfg.url
+        );
+        match send(&client, remote_write_cfg, timeseries) {
+            Ok(_) => {
+                queue.pop_front();
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+    }
+
+    let mut backoff = BACKOFF.lock().unwrap();
+    *backoff = match (&result, *backoff) {
+        (Ok(_), _) => None,
+        (Err(_), None) => {
+            let delay = Duration::from_secs(constants::REMOTE_WRITE_RETRY_INITIAL_DELAY);
+            Some((Instant::now() + delay, delay))
+        }
+        (Err(_), Some((_, previous))) => {
+            let delay =
+                (previous * 2).min(Duration::from_secs(constants::REMOTE_WRITE_RETRY_MAX_DELAY));
+            Some((Instant::now() + delay, delay))
+        }
+    };
+
+    exporter::REMOTE_WRITE_QUEUE.set(queue.len() as i64);
+    result
+}
+
+pub fn write(
+    cfg: &config::Configuration,
+    remote_write_cfg: &config::RemoteWriteConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let max_queue = remote_write_cfg
+        .max_queue
+        .unwrap_or(constants::DEFAULT_REMOTE_WRITE_MAX_QUEUE);
+    let mut queue = QUEUE.lock().unwrap();
+
+    queue.push_back(collect(remote_write_cfg));
+    while queue.len() > max_queue {
+        warn!("Remote write queue is full, dropping oldest data");
+        queue.pop_front();
+    }
+
+    flush(cfg, remote_write_cfg, &mut queue)
+}
+
+// Called periodically by the background refresh, so queued data doesn't have to wait for
+// the next update
+pub fn retry(
+    cfg: &config::Configuration,
+    remote_write_cfg: &config::RemoteWriteConfiguration,
+) -> Result<(), Box<dyn Error>> {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.is_empty() {
+        return Ok(());
+    }
+    if BACKOFF
+        .lock()
+        .unwrap()
+        .is_some_and(|(next_attempt, _)| Instant::now() < next_attempt)
+    {
+        return Ok(());
+    }
+
+    debug!(
+        "Retrying to send {} queued updates to {}",
+        queue.len(),
+        remote_write_cfg.url
+    );
+    flush(cfg, remote_write_cfg, &mut queue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(timeseries: &TimeSeries) -> Vec<(&str, &str)> {
+        timeseries
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_collect() {
+        let data = serde_json::from_str(
+            r#"{"name": "Remote Write Test", "sys": {"country": "DE"}, "main": {"temp": 21.5}}"#,
+        )
+        .unwrap();
+        exporter::set_location_metrics("remote write test", &data);
+
+        let remote_write_cfg: config::RemoteWriteConfiguration =
+            serde_yaml::from_str("url: http://localhost:9090/api/v1/write\nlabels: {a: b, z: y}")
+                .unwrap();
+        let timeseries: Vec<TimeSeries> = collect(&remote_write_cfg)
+            .into_iter()
+            .filter(|v| labels(v).contains(&("name", "Remote Write Test")))
+            .collect();
+        assert_eq!(timeseries.len(), 1);
+        assert_eq!(
+            labels(&timeseries[0]),
+            vec![
+                ("__name__", constants::METRIC_TEMP_NAME),
+                ("a", "b"),
+                ("country", "DE"),
+                ("name", "Remote Write Test"),
+                ("z", "y"),
+            ]
+        );
+        assert_eq!(timeseries[0].samples[0].value, 21.5);
+
+        // Metrics of the exporter itself are not sent
+        assert!(!collect(&remote_write_cfg)
+            .iter()
+            .flat_map(|v| v.labels.iter())
+            .any(|l| l.name == "__name__" && l.value.starts_with("openweathermap_exporter_")));
+    }
+}
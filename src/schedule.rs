@@ -0,0 +1,224 @@
+use crate::config;
+use crate::constants;
+use crate::exporter;
+use crate::outputs;
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref SCHEDULE: Mutex<Schedule> = Mutex::new(Schedule::default());
+}
+
+#[derive(Default)]
+struct Schedule {
+    interval: Option<Duration>,
+    next_update: HashMap<String, Instant>,
+}
+
+// Without a fixed refresh interval, an API call budget or push outputs every scrape updates all locations
+pub fn refresh_interval(cfg: &config::Configuration) -> Option<Duration> {
+    let min_interval = budget_interval(cfg);
+
+    match (cfg.refresh_interval, min_interval) {
+        (Some(configured), Some(min_interval)) => {
+            let configured = Duration::from_secs(configured);
+            if configured < min_interval {
+                warn!(
+                    "Refresh interval of {} seconds exceeds the API call budget, using {} seconds instead",
+                    configured.as_secs(),
+                    min_interval.as_secs()
+                );
+                Some(min_interval)
+            } else {
+                Some(configured)
+            }
+        }
+        (Some(configured), None) => Some(Duration::from_secs(configured)),
+        // Push outputs refresh in the background and would query every location on every run.
+        // There is only one schedule, so this also applies to scrapes
+        (None, None) if outputs::enabled(cfg) => {
+            info!(
+                "No refresh interval configured, using the default of {} seconds of outputs for scrapes too",
+                constants::DEFAULT_BACKGROUND_REFRESH_INTERVAL
+            );
+            Some(Duration::from_secs(
+                constants::DEFAULT_BACKGROUND_REFRESH_INTERVAL,
+            ))
+        }
+        (None, min_interval) => min_interval,
+    }
+}
+
+// Fastest refresh interval of all locations that stays within every configured limit
+fn budget_interval(cfg: &config::Configuration) -> Option<Duration> {
+    let budget = cfg.budget.as_ref()?;
+    let calls = (cfg.locations.len() as u64 * constants::OWM_CALLS_PER_LOCATION) as f64;
+
+    let limits = [
+        (budget.calls_per_minute, 60.0),
+        (budget.calls_per_day, 86400.0),
+        // use the shortest month to be on the safe side
+        (budget.calls_per_month, 28.0 * 86400.0),
+    ];
+
+    let interval = limits
+        .iter()
+        .filter_map(|(limit, period)| limit.map(|l| period * calls / l.max(1) as f64))
+        .fold(0.0, f64::max);
+
+    Some(Duration::from_secs_f64(
+        interval * constants::BUDGET_HEADROOM,
+    ))
+}
+
+pub fn init(cfg: &config::Configuration) {
+    let interval = refresh_interval(cfg);
+    let mut schedule = SCHEDULE.lock().unwrap();
+    let now = Instant::now();
+
+    let previous = std::mem::take(&mut schedule.next_update);
+    schedule.interval = interval;
+
+    let interval = match interval {
+        Some(v) => v,
+        None => {
+            exporter::REFRESH_INTERVAL.set(0.0);
+            return;
+        }
+    };
+
+    info!(
+        "Updating weather data of each location every {} seconds",
+        interval.as_secs_f64()
+    );
+    exporter::REFRESH_INTERVAL.set(interval.as_secs_f64());
+
+    // Spread the first update of all locations evenly across the interval,
+    // locations that are already scheduled (on configuration reload) keep their schedule
+    let count = cfg.locations.len() as u32;
+    for (i, location) in cfg.locations.iter().enumerate() {
+        let location = location.to_string();
+        let next = match previous.get(&location) {
+            Some(v) => *v,
+            None => now + interval / count * i as u32,
+        };
+        schedule.next_update.insert(location, next);
+    }
+}
+
+// Refresh interval of the current configuration, None if every scrape updates all locations
+pub fn interval() -> Option<Duration> {
+    SCHEDULE.lock().unwrap().interval
+}
+
+// Updates don't run concurrently (see exporter::update_metrics_once), so a location can't
+// become due twice between is_due and done
+pub fn is_due(location: &str) -> bool {
+    let schedule = SCHEDULE.lock().unwrap();
+    match schedule.next_update.get(location) {
+        Some(next) => Instant::now() >= *next,
+        None => true,
+    }
+}
+
+// Schedule the next update after a successful update
+pub fn done(location: &str) {
+    let interval = match SCHEDULE.lock().unwrap().interval {
+        Some(v) => v,
+        None => return,
+    };
+    schedule_next(location, interval);
+}
+
+// Failed updates are retried earlier, but not before the retry interval
+pub fn failed(location: &str) {
+    let interval = match SCHEDULE.lock().unwrap().interval {
+        Some(v) => v,
+        None => return,
+    };
+    schedule_next(
+        location,
+        interval.min(Duration::from_secs(
+            constants::FAILED_REFRESH_RETRY_INTERVAL,
+        )),
+    );
+}
+
+fn schedule_next(location: &str, interval: Duration) {
+    let mut schedule = SCHEDULE.lock().unwrap();
+
+    let max_jitter = interval.mul_f64(constants::REFRESH_JITTER_FRACTION);
+    let jitter = max_jitter.mul_f64(rand::thread_rng().gen::<f64>());
+    schedule
+        .next_update
+        .insert(location.to_string(), Instant::now() + interval + jitter);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration(yaml: &str) -> config::Configuration {
+        config::test_configuration(&format!("locations: [Berlin, Paris]\n{}", yaml))
+    }
+
+    #[test]
+    fn test_budget_interval() {
+        assert_eq!(budget_interval(&configuration("")), None);
+
+        // 2 locations with 60 calls per minute and 1000 calls per day, the daily limit is stricter
+        let interval = budget_interval(&configuration(
+            "budget: {calls_per_minute: 60, calls_per_day: 1000}",
+        ))
+        .unwrap();
+        assert_eq!(
+            interval,
+            Duration::from_secs_f64(86400.0 * 2.0 / 1000.0 * constants::BUDGET_HEADROOM)
+        );
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        assert_eq!(refresh_interval(&configuration("")), None);
+        assert_eq!(
+            refresh_interval(&configuration("refresh_interval: 300")),
+            Some(Duration::from_secs(300))
+        );
+
+        // A configured interval can't exceed the budget
+        let budget = "budget: {calls_per_day: 1000}\n";
+        let min_interval = budget_interval(&configuration(budget)).unwrap();
+        assert_eq!(
+            refresh_interval(&configuration(&format!("{}refresh_interval: 60", budget))),
+            Some(min_interval)
+        );
+        assert_eq!(
+            refresh_interval(&configuration(&format!("{}refresh_interval: 3600", budget))),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn test_refresh_interval_outputs() {
+        // The default of push outputs applies to scrapes too
+        assert_eq!(
+            refresh_interval(&configuration(
+                "pushgateway: {url: 'http://localhost:9091'}"
+            )),
+            Some(Duration::from_secs(
+                constants::DEFAULT_BACKGROUND_REFRESH_INTERVAL
+            ))
+        );
+        assert_eq!(
+            refresh_interval(&configuration(
+                "pushgateway: {url: 'http://localhost:9091'}\nrefresh_interval: 60"
+            )),
+            Some(Duration::from_secs(60))
+        );
+    }
+}
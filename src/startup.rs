@@ -0,0 +1,174 @@
+use crate::budget;
+use crate::config;
+use crate::exporter;
+use crate::http;
+use crate::openweathermap;
+use crate::schedule;
+
+use log::{error, info, warn};
+use std::error::Error;
+
+struct CheckResult {
+    location: String,
+    name: String,
+    country: String,
+    id: String,
+    status: String,
+}
+
+// Request weather data of every location once, fail on an invalid API key
+pub fn check_locations(cfg: &config::Configuration) -> Result<(), Box<dyn Error>> {
+    let mut client = http::build_api_client(cfg)?;
+    let mut results: Vec<CheckResult> = Vec::new();
+
+    info!("Checking API key and {} locations", cfg.locations.len());
+
+    for location_cfg in &cfg.locations {
+        let location = &location_cfg.to_string();
+        let mut result = CheckResult {
+            location: location.clone(),
+            name: "-".to_string(),
+            country: "-".to_string(),
+            id: "-".to_string(),
+            status: "ok".to_string(),
+        };
+
+        if !budget::acquire() {
+            warn!(
+                "API call budget exhausted, can't check location {}",
+                location
+            );
+            result.status = "not checked, API call budget exhausted".to_string();
+            results.push(result);
+            continue;
+        }
+
+        match exporter::fetch_location(&mut client, cfg, location_cfg, None) {
+            Ok(data) => {
+                result.name = data.name.clone();
+                result.country = data.country().to_string();
+                if let Some(id) = data.id {
+                    result.id = id.to_string();
+                }
+                schedule::done(location);
+                exporter::set_location_metrics(location, &data);
+            }
+            Err(e) => {
+                schedule::failed(location);
+                if let Some(api_error) = e.downcast_ref::<openweathermap::ApiError>() {
+                    if api_error.kind == openweathermap::ApiErrorKind::InvalidApiKey {
+                        bail!("OpenWeatherMap rejected the API key: {}", api_error.message);
+                    }
+                }
+                error!("Can't fetch weather data for {}: {}", location, e);
+                result.status = exporter::error_kind(e.as_ref()).to_string();
+            }
+        };
+
+        results.push(result);
+    }
+
+    print!("{}", summary(&results));
+    Ok(())
+}
+
+// Table of the results with a header, columns are aligned
+fn summary(results: &[CheckResult]) -> String {
+    let header = CheckResult {
+        location: "Location".to_string(),
+        name: "Name".to_string(),
+        country: "Country".to_string(),
+        id: "ID".to_string(),
+        status: "Status".to_string(),
+    };
+
+    let width = |f: fn(&CheckResult) -> &str| {
+        results
+            .iter()
+            .chain(std::iter::once(&header))
+            .map(|r| f(r).chars().count())
+            .max()
+            .unwrap_or_default()
+    };
+    let location_width = width(|r| &r.location);
+    let name_width = width(|r| &r.name);
+    let country_width = width(|r| &r.country);
+    let id_width = width(|r| &r.id);
+
+    let mut result = String::new();
+    for r in std::iter::once(&header).chain(results.iter()) {
+        result.push_str(&format!(
+            "{:<lw$}  {:<nw$}  {:<cw$}  {:<iw$}  {}\n",
+            r.location,
+            r.name,
+            r.country,
+            r.id,
+            r.status,
+            lw = location_width,
+            nw = name_width,
+            cw = country_width,
+            iw = id_width
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn check_result(
+        location: &str,
+        name: &str,
+        country: &str,
+        id: &str,
+        status: &str,
+    ) -> CheckResult {
+        CheckResult {
+            location: location.to_string(),
+            name: name.to_string(),
+            country: country.to_string(),
+            id: id.to_string(),
+            status: status.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let results = [
+            check_result("London,gb", "London", "GB", "2643743", "ok"),
+            check_result("Köln", "-", "-", "-", "unknown_location"),
+        ];
+        assert_eq!(
+            summary(&results),
+            "Location   Name    Country  ID       Status\n\
+             London,gb  London  GB       2643743  ok\n\
+             Köln       -       -        -        unknown_location\n"
+        );
+    }
+
+    #[test]
+    fn test_check_locations_failed() {
+        // Proxy rejecting all requests, failed locations don't stop the exporter
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.read(&mut [0; 4096]);
+                let _ = stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n");
+            }
+        });
+        let cfg = config::test_configuration(&format!(
+            "locations: [Startup Check Test]\nretry: {{retries: 0}}\nproxy: {{url: 'http://127.0.0.1:{}'}}",
+            port
+        ));
+
+        assert!(check_locations(&cfg).is_ok());
+        assert!(exporter::observations()
+            .iter()
+            .all(|(location, _)| location != "Startup Check Test"));
+    }
+}
@@ -0,0 +1,407 @@
+use crate::config;
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerified,
+    ClientCertVerifier,
+};
+use rustls::{Certificate, DistinguishedName, PrivateKey, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+lazy_static! {
+    // Server configuration and the settings it was built from
+    static ref SERVER_CONFIG: Mutex<Option<(Settings, Arc<ServerConfig>)>> = Mutex::new(None);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Settings {
+    files: Vec<FileState>,
+    min_version: Option<String>,
+    max_version: Option<String>,
+    client_auth_optional: bool,
+    client_allowed_sans: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct FileState {
+    name: String,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+fn file_states(cfg: &config::Configuration) -> Vec<FileState> {
+    [
+        &cfg.tls_cert_file,
+        &cfg.tls_key_file,
+        &cfg.tls_client_ca_file,
+    ]
+    .iter()
+    .filter_map(|v| v.as_ref())
+    .map(|name| {
+        let metadata = fs::metadata(name).ok();
+        FileState {
+            name: name.clone(),
+            modified: metadata.as_ref().and_then(|v| v.modified().ok()),
+            len: metadata.map(|v| v.len()).unwrap_or_default(),
+        }
+    })
+    .collect()
+}
+
+fn load_certificates(file: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let pem = match fs::read(file) {
+        Ok(v) => v,
+        Err(e) => bail!("can't read {}: {}", file, e),
+    };
+    let certificates = match rustls_pemfile::certs(&mut BufReader::new(pem.as_slice())) {
+        Ok(v) => v,
+        Err(e) => bail!("can't parse {}: {}", file, e),
+    };
+    if certificates.is_empty() {
+        bail!("no certificates found in {}", file);
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(file: &str) -> Result<PrivateKey, Box<dyn Error>> {
+    let pem = match fs::read(file) {
+        Ok(v) => v,
+        Err(e) => bail!("can't read {}: {}", file, e),
+    };
+    let items = match rustls_pemfile::read_all(&mut BufReader::new(pem.as_slice())) {
+        Ok(v) => v,
+        Err(e) => bail!("can't parse {}: {}", file, e),
+    };
+    for item in items {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        };
+    }
+    bail!("no private key found in {}", file);
+}
+
+// Client certificates must be valid for one of the allowed DNS names or IP addresses, as
+// client_allowed_sans of the exporter-toolkit
+struct AllowedSansVerifier {
+    verifier: Arc<dyn ClientCertVerifier>,
+    allowed_sans: Vec<String>,
+}
+
+impl ClientCertVerifier for AllowedSansVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.verifier.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.verifier.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.verifier.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .verifier
+            .verify_client_cert(end_entity, intermediates, now)?;
+
+        let certificate =
+            webpki::EndEntityCert::try_from(end_entity.0.as_slice()).map_err(|_| {
+                rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
+            })?;
+        let allowed = self.allowed_sans.iter().any(|san| {
+            webpki::SubjectNameRef::try_from_ascii_str(san)
+                .map(|v| certificate.verify_is_valid_for_subject_name(v).is_ok())
+                .unwrap_or(false)
+        });
+        if !allowed {
+            warn!("Rejecting client certificate, subject alternative names are not allowed");
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::NotValidForName,
+            ));
+        }
+        Ok(verified)
+    }
+}
+
+fn build_server_config(
+    cfg: &config::Configuration,
+    cert_file: &str,
+    key_file: &str,
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let versions: &[&rustls::SupportedProtocolVersion] = match (
+        cfg.tls_min_version.as_deref(),
+        cfg.tls_max_version.as_deref(),
+    ) {
+        (Some("1.3"), _) => &[&rustls::version::TLS13],
+        (_, Some("1.2")) => &[&rustls::version::TLS12],
+        _ => &[&rustls::version::TLS12, &rustls::version::TLS13],
+    };
+    let builder = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?;
+
+    let builder = match &cfg.tls_client_ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(ca_file)? {
+                roots.add(&certificate)?;
+            }
+            let verifier = if cfg.tls_client_auth_optional {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            };
+            if cfg.tls_client_allowed_sans.is_empty() {
+                builder.with_client_cert_verifier(verifier)
+            } else {
+                builder.with_client_cert_verifier(Arc::new(AllowedSansVerifier {
+                    verifier,
+                    allowed_sans: cfg.tls_client_allowed_sans.clone(),
+                }))
+            }
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config =
+        builder.with_single_cert(load_certificates(cert_file)?, load_private_key(key_file)?)?;
+    Ok(server_config)
+}
+
+// Returns None if TLS is not configured. Certificate, key and CA files are re-read if they
+// have changed, if they can't be loaded the previous configuration is kept.
+pub fn server_config(
+    cfg: &config::Configuration,
+) -> Result<Option<Arc<ServerConfig>>, Box<dyn Error>> {
+    let (cert_file, key_file) = match (&cfg.tls_cert_file, &cfg.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        _ => return Ok(None),
+    };
+
+    let settings = Settings {
+        files: file_states(cfg),
+        min_version: cfg.tls_min_version.clone(),
+        max_version: cfg.tls_max_version.clone(),
+        client_auth_optional: cfg.tls_client_auth_optional,
+        client_allowed_sans: cfg.tls_client_allowed_sans.clone(),
+    };
+    let mut current = SERVER_CONFIG.lock().unwrap();
+    if let Some((current_settings, server_config)) = &*current {
+        if *current_settings == settings {
+            return Ok(Some(server_config.clone()));
+        }
+    }
+
+    match build_server_config(cfg, cert_file, key_file) {
+        Ok(v) => {
+            if current.is_some() {
+                info!("Reloaded TLS certificate from {}", cert_file);
+            }
+            let server_config = Arc::new(v);
+            *current = Some((settings, server_config.clone()));
+            Ok(Some(server_config))
+        }
+        Err(e) => match current.take() {
+            Some((_, server_config)) => {
+                warn!(
+                    "Can't reload TLS certificate, using previous certificate: {}",
+                    e
+                );
+                *current = Some((settings, server_config.clone()));
+                Ok(Some(server_config))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ClientConnection, ServerConnection, ServerName};
+
+    const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/ca.pem");
+    const SERVER_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/server.pem");
+    const SERVER_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/server.key");
+    const SERVER2_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/server2.pem");
+    const SERVER2_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/server2.key");
+    const CLIENT_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/client.pem");
+    const CLIENT_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/client.key");
+
+    fn client_config(
+        versions: &[&'static rustls::SupportedProtocolVersion],
+        with_certificate: bool,
+    ) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(&load_certificates(CA).unwrap()[0]).unwrap();
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_root_certificates(roots);
+        if with_certificate {
+            builder
+                .with_client_auth_cert(
+                    load_certificates(CLIENT_CERT).unwrap(),
+                    load_private_key(CLIENT_KEY).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        }
+    }
+
+    // Handshake in memory, returns the certificate presented by the server
+    fn handshake(
+        server_config: Arc<ServerConfig>,
+        client_config: ClientConfig,
+    ) -> Result<Certificate, rustls::Error> {
+        let mut server = ServerConnection::new(server_config)?;
+        let mut client = ClientConnection::new(
+            Arc::new(client_config),
+            ServerName::try_from("localhost").unwrap(),
+        )?;
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(client.peer_certificates().unwrap()[0].clone());
+            }
+            let mut buffer = Vec::new();
+            client.write_tls(&mut buffer).unwrap();
+            server.read_tls(&mut buffer.as_slice()).unwrap();
+            server.process_new_packets()?;
+            buffer.clear();
+            server.write_tls(&mut buffer).unwrap();
+            client.read_tls(&mut buffer.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        panic!("handshake not finished");
+    }
+
+    #[test]
+    fn test_load_files() {
+        assert_eq!(load_certificates(SERVER_CERT).unwrap().len(), 1);
+        assert!(load_private_key(SERVER_KEY).is_ok());
+
+        assert!(load_certificates("/nonexistent.pem").is_err());
+        assert!(load_private_key("/nonexistent.key").is_err());
+        // Certificate and key files mixed up
+        assert!(load_certificates(SERVER_KEY).is_err());
+        assert!(load_private_key(SERVER_CERT).is_err());
+    }
+
+    #[test]
+    fn test_server_config_disabled() {
+        let cfg = config::test_configuration("");
+        assert!(server_config(&cfg).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_min_max_version() {
+        let tls12 = &[&rustls::version::TLS12][..];
+        let tls13 = &[&rustls::version::TLS13][..];
+        let mut cfg = config::test_configuration("");
+
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server.clone(), client_config(tls12, false)).is_ok());
+        assert!(handshake(server, client_config(tls13, false)).is_ok());
+
+        cfg.tls_min_version = Some("1.3".to_string());
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server.clone(), client_config(tls12, false)).is_err());
+        assert!(handshake(server, client_config(tls13, false)).is_ok());
+
+        cfg.tls_min_version = None;
+        cfg.tls_max_version = Some("1.2".to_string());
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server.clone(), client_config(tls12, false)).is_ok());
+        assert!(handshake(server, client_config(tls13, false)).is_err());
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let versions = rustls::DEFAULT_VERSIONS;
+        let mut cfg = config::test_configuration("");
+        cfg.tls_client_ca_file = Some(CA.to_string());
+
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server.clone(), client_config(versions, true)).is_ok());
+        assert!(handshake(server, client_config(versions, false)).is_err());
+
+        cfg.tls_client_auth_optional = true;
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server.clone(), client_config(versions, true)).is_ok());
+        assert!(handshake(server, client_config(versions, false)).is_ok());
+
+        // The client certificate is issued for client.example
+        cfg.tls_client_auth_optional = false;
+        cfg.tls_client_allowed_sans = vec!["client.example".to_string()];
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server, client_config(versions, true)).is_ok());
+
+        cfg.tls_client_allowed_sans = vec!["other.example".to_string()];
+        let server = Arc::new(build_server_config(&cfg, SERVER_CERT, SERVER_KEY).unwrap());
+        assert!(handshake(server, client_config(versions, true)).is_err());
+    }
+
+    #[test]
+    fn test_server_config_reload() {
+        let path = |name: &str| {
+            std::env::temp_dir()
+                .join(format!(
+                    "openweathermap-exporter-test-{}-{}",
+                    std::process::id(),
+                    name
+                ))
+                .to_string_lossy()
+                .to_string()
+        };
+        let (cert_file, key_file) = (path("server.pem"), path("server.key"));
+        fs::copy(SERVER_CERT, &cert_file).unwrap();
+        fs::copy(SERVER_KEY, &key_file).unwrap();
+        let mut cfg = config::test_configuration("");
+        cfg.tls_cert_file = Some(cert_file.clone());
+        cfg.tls_key_file = Some(key_file.clone());
+        let client = || client_config(rustls::DEFAULT_VERSIONS, false);
+
+        // Unchanged files aren't loaded again
+        let first = server_config(&cfg).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &server_config(&cfg).unwrap().unwrap()));
+        assert_eq!(
+            handshake(first.clone(), client()).unwrap(),
+            load_certificates(SERVER_CERT).unwrap()[0]
+        );
+
+        fs::copy(SERVER2_CERT, &cert_file).unwrap();
+        fs::copy(SERVER2_KEY, &key_file).unwrap();
+        let second = server_config(&cfg).unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            handshake(second.clone(), client()).unwrap(),
+            load_certificates(SERVER2_CERT).unwrap()[0]
+        );
+
+        // Invalid files are ignored, the previous certificate is kept
+        fs::write(&cert_file, "invalid").unwrap();
+        assert!(Arc::ptr_eq(&second, &server_config(&cfg).unwrap().unwrap()));
+
+        fs::remove_file(&cert_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+    }
+}
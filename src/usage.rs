@@ -18,7 +18,7 @@ Public License Version 3. (http://www.gnu.org/copyleft/gpl.html)
 pub fn show_usage() {
     show_version();
     println!(
-        "Usage: {} [-D|--debug] [-V|--version] -c <config>|--config=<config> [-h|--help] [-l <address>|--listen=<address>]
+        "Usage: {} [-D|--debug] [-V|--version] -c <config>|--config=<config> [--check-config] [-h|--help] [-l <address>|--listen=<address>] [-q|--quiet] [--once] [--textfile=<file>]
 
     -D                  Enable debug mode
     --debug
@@ -29,14 +29,23 @@ pub fn show_usage() {
     -c <config>         Configuration file
     --config=<config>
 
+    --check-config      Check configuration file and exit
+
     -h                  Show this help text
     --help
 
     -l <address>        Listen on <address> for scrape requests
-    --listen=<address>  Default: {}
+    --listen=<address>  Overrides listen address of the configuration file
+                        Default: {}
 
     -q                  Quiet operation. Only log warning
     --quiet             and error messages
+
+    --once              Update all locations once, print metrics
+                        to standard output and exit
+
+    --textfile=<file>   Update all locations once, write metrics
+                        to <file> and exit
 ",
         constants::NAME, constants::DEFAULT_PROMETHEUS_ADDRESS
     );
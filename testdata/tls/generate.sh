@@ -0,0 +1,29 @@
+#!/bin/sh
+# Certificates for the tests of src/tls.rs, valid for 100 years
+set -e
+cd "$(dirname "$0")"
+DAYS=36500
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+sign() {
+    openssl req -new -key "$1.key" -subj "/CN=$1" |
+        openssl x509 -req -CA ca.pem -CAkey ca.key -CAcreateserial -days $DAYS \
+            -extfile "$2" -out "$1.pem"
+}
+
+key ca
+openssl req -x509 -new -key ca.key -subj "/CN=test-ca" -days $DAYS -out ca.pem
+
+printf 'subjectAltName=DNS:localhost,IP:127.0.0.1\nextendedKeyUsage=serverAuth\n' > server.ext
+printf 'subjectAltName=DNS:client.example\nextendedKeyUsage=clientAuth\n' > client.ext
+for name in server server2; do
+    key $name
+    sign $name server.ext
+done
+key client
+sign client client.ext
+
+rm -f ca.key ca.srl server.ext client.ext